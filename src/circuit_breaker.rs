@@ -0,0 +1,305 @@
+use actix::Addr;
+use ha_mqtt_discovery::{
+    mqtt::{
+        binary_sensor::BinarySensor, common::EntityCategory,
+        device_classes::BinarySensorDeviceClass,
+    },
+    Entity,
+};
+use log::{info, warn};
+use serde_json::json;
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    misc::{app_infos, hostname, HumanReadable, Sluggable},
+    mqtt::{EntityConfiguration, MqttActor, PublishEntityData, Topics},
+};
+
+/// Delay before tasks rejected during a half-open probe check the breaker again.
+const PROBE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct CircuitBreakerConfiguration {
+    /// Consecutive failures opening the circuit
+    pub failure_threshold: u32,
+    /// Duration during which calls are rejected once the circuit is open
+    pub open_duration: Duration,
+    pub topics: Topics,
+}
+
+/// Stops every task of a provider from calling its cloud API once consecutive calls failed, until
+/// a single probe call succeeds.
+///
+/// The state is published as a Home Assistant diagnostic binary sensor of the bridge device.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    provider: String,
+    config: CircuitBreakerConfiguration,
+    circuit: Arc<Mutex<Circuit>>,
+    mqtt_addr: Addr<MqttActor>,
+}
+
+impl CircuitBreaker {
+    pub fn new<C: Into<CircuitBreakerConfiguration>>(
+        provider: &str,
+        configuration: C,
+        mqtt_addr: Addr<MqttActor>,
+    ) -> Self {
+        let breaker = Self {
+            provider: provider.to_string(),
+            config: configuration.into(),
+            circuit: Arc::new(Mutex::new(Circuit::default())),
+            mqtt_addr,
+        };
+        breaker
+            .mqtt_addr
+            .do_send(EntityConfiguration(breaker.entity()));
+        breaker.publish_state(CircuitState::Closed);
+        breaker
+    }
+
+    /// Grants a call, or returns the delay before asking again while the circuit is open.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut circuit = self.circuit.lock().unwrap();
+        let previous_state = circuit.state();
+        let result = circuit.try_acquire(Instant::now(), self.config.open_duration);
+        if previous_state != circuit.state() {
+            info!(
+                "{} circuit breaker is half-open, probing the API",
+                self.provider
+            );
+            self.publish_state(circuit.state());
+        }
+        result
+    }
+
+    /// Reports the outcome of a call granted by [CircuitBreaker::try_acquire].
+    pub fn record(&self, success: bool) {
+        let mut circuit = self.circuit.lock().unwrap();
+        let previous_state = circuit.state();
+        circuit.record(
+            success,
+            Instant::now(),
+            self.config.failure_threshold,
+            self.config.open_duration,
+        );
+        let state = circuit.state();
+        if previous_state != state {
+            match state {
+                CircuitState::Open => warn!(
+                    "{} circuit breaker is open, pausing API calls for {}",
+                    self.provider,
+                    self.config.open_duration.prettify()
+                ),
+                _ => info!("{} circuit breaker is {state}", self.provider),
+            }
+            self.publish_state(state);
+        }
+    }
+
+    fn unique_id(&self) -> String {
+        format!(
+            "{}-{}-{}-circuit-breaker",
+            app_infos::name(),
+            hostname(),
+            self.provider.slug()
+        )
+        .slug()
+    }
+
+    fn state_topic(&self) -> String {
+        self.config
+            .topics
+            .bridge(&format!("circuit-breaker/{}", self.provider.slug()))
+    }
+
+    fn entity(&self) -> Entity {
+        let unique_id = self.unique_id();
+        Entity::BinarySensor(
+            BinarySensor::default()
+                .name(format!("{} API circuit breaker", self.provider))
+                .unique_id(&unique_id)
+                .object_id(&unique_id)
+                .state_topic(self.state_topic())
+                .value_template("{{ value_json.state != 'closed' }}")
+                .payload_on("True")
+                .payload_off("False")
+                .device_class(BinarySensorDeviceClass::Problem)
+                .entity_category(EntityCategory::Diagnostic)
+                .origin(app_infos::origin())
+                .device(app_infos::device())
+                .availability(vec![self.config.topics.bridge_availability()]),
+        )
+    }
+
+    fn publish_state(&self, state: CircuitState) {
+        self.mqtt_addr.do_send(PublishEntityData::new(
+            self.state_topic(),
+            json!({ "state": state.to_string() }),
+        ));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Circuit {
+    Closed {
+        consecutive_failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single probe call is in flight, started at `since`
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit::Closed {
+            consecutive_failures: 0,
+        }
+    }
+}
+
+impl Circuit {
+    fn state(&self) -> CircuitState {
+        match self {
+            Circuit::Closed { .. } => CircuitState::Closed,
+            Circuit::Open { .. } => CircuitState::Open,
+            Circuit::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Once the open duration is over, the first caller probes the API. Another probe is granted
+    /// when the previous one never reported its outcome.
+    fn try_acquire(&mut self, now: Instant, open_duration: Duration) -> Result<(), Duration> {
+        match *self {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } if now < until => Err(until - now),
+            Circuit::HalfOpen { since } if now < since + open_duration => Err(PROBE_RETRY_DELAY),
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => {
+                *self = Circuit::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    fn record(
+        &mut self,
+        success: bool,
+        now: Instant,
+        failure_threshold: u32,
+        open_duration: Duration,
+    ) {
+        *self = match (&*self, success) {
+            (_, true) => Circuit::default(),
+            (
+                Circuit::Closed {
+                    consecutive_failures,
+                },
+                false,
+            ) if consecutive_failures + 1 < failure_threshold => Circuit::Closed {
+                consecutive_failures: consecutive_failures + 1,
+            },
+            // failures of calls granted before the circuit opened don't extend the open duration
+            (Circuit::Open { until }, false) => Circuit::Open { until: *until },
+            (Circuit::Closed { .. } | Circuit::HalfOpen { .. }, false) => Circuit::Open {
+                until: now + open_duration,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Circuit, CircuitState, PROBE_RETRY_DELAY};
+
+    const OPEN_DURATION: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut circuit = Circuit::default();
+
+        circuit.record(false, now, 3, OPEN_DURATION);
+        circuit.record(false, now, 3, OPEN_DURATION);
+        circuit.record(true, now, 3, OPEN_DURATION);
+        circuit.record(false, now, 3, OPEN_DURATION);
+        circuit.record(false, now, 3, OPEN_DURATION);
+        assert_eq!(
+            circuit.state(),
+            CircuitState::Closed,
+            "a success resets the failures count"
+        );
+        assert_eq!(circuit.try_acquire(now, OPEN_DURATION), Ok(()));
+
+        circuit.record(false, now, 3, OPEN_DURATION);
+        assert_eq!(circuit.state(), CircuitState::Open);
+        assert_eq!(
+            circuit.try_acquire(now + Duration::from_secs(20), OPEN_DURATION),
+            Err(Duration::from_secs(40)),
+            "calls are rejected until the end of the open duration"
+        );
+    }
+
+    #[test]
+    fn grants_a_single_probe_once_open_duration_is_over() {
+        let now = Instant::now();
+        let mut circuit = Circuit::Open { until: now };
+
+        assert_eq!(circuit.try_acquire(now, OPEN_DURATION), Ok(()));
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        assert_eq!(
+            circuit.try_acquire(now, OPEN_DURATION),
+            Err(PROBE_RETRY_DELAY),
+            "other calls wait for the probe outcome"
+        );
+        assert_eq!(
+            circuit.try_acquire(now + OPEN_DURATION, OPEN_DURATION),
+            Ok(()),
+            "a new probe is granted when the previous one never completed"
+        );
+    }
+
+    #[test]
+    fn closes_or_opens_again_depending_on_probe_outcome() {
+        let now = Instant::now();
+
+        let mut circuit = Circuit::HalfOpen { since: now };
+        circuit.record(true, now, 3, OPEN_DURATION);
+        assert_eq!(circuit, Circuit::default());
+
+        let mut circuit = Circuit::HalfOpen { since: now };
+        circuit.record(false, now, 3, OPEN_DURATION);
+        assert_eq!(
+            circuit,
+            Circuit::Open {
+                until: now + OPEN_DURATION
+            }
+        );
+    }
+}
@@ -1,40 +1,94 @@
 use anyhow::{anyhow, bail, Error};
 use anyhow::{Context, Result};
-use chrono::TimeDelta;
+use chrono::{Month, NaiveTime, TimeDelta};
+use chrono_tz::Tz;
 use regex::Regex;
-use std::{ops::RangeInclusive, time::Duration};
+use std::{fmt::Display, fs, net::IpAddr, ops::RangeInclusive, path::PathBuf, time::Duration};
 
+use crate::repeat::policy::{Jitter, Schedule, ScheduleWindow};
+
+/// Parse either a compound duration like `1d12h` or `2m30s`, or an ISO-8601 duration like
+/// `PT1H30M`.
 pub fn parse_time_delta(arg: &str) -> Result<Duration, Error> {
     let arg = arg.trim();
-    let (amount, unit) = Regex::new(r"^(\d+)(ms|s|m|h|d)$")
-        .unwrap()
-        .captures(arg)
-        .map(|captures| captures.extract())
-        .map(|(_, [amount, unit])| (amount, unit))
-        .ok_or(anyhow!("invalid duration: {arg}"))?;
-    let amount: i64 = amount.parse()?;
-    match unit {
-        "d" => Ok(TimeDelta::days(amount)),
-        "h" => Ok(TimeDelta::hours(amount)),
-        "m" => Ok(TimeDelta::minutes(amount)),
-        "s" => Ok(TimeDelta::seconds(amount)),
-        "ms" => Ok(TimeDelta::milliseconds(amount)),
-        _ => Err(anyhow!("invalid duration: {arg}")),
-    }?
-    .abs()
+    if arg.starts_with('P') {
+        parse_iso8601_time_delta(arg)
+    } else {
+        parse_compound_time_delta(arg)
+    }
+    .ok_or(anyhow!("invalid duration: {arg}"))?
     .to_std()
     .map_err(|err| Error::new(err).context(format!("invalid duration: {arg}")))
 }
 
+/// A sequence of `<amount><unit>`, units being `d`, `h`, `m`, `s` or `ms`.
+fn parse_compound_time_delta(arg: &str) -> Option<TimeDelta> {
+    if !Regex::new(r"^(\d+(ms|s|m|h|d))+$").unwrap().is_match(arg) {
+        return None;
+    }
+    Regex::new(r"(\d+)(ms|s|m|h|d)")
+        .unwrap()
+        .captures_iter(arg)
+        .map(|captures| captures.extract())
+        .try_fold(TimeDelta::zero(), |total, (_, [amount, unit])| {
+            let amount: i64 = amount.parse().ok()?;
+            let time_delta = match unit {
+                "d" => TimeDelta::try_days(amount),
+                "h" => TimeDelta::try_hours(amount),
+                "m" => TimeDelta::try_minutes(amount),
+                "s" => TimeDelta::try_seconds(amount),
+                "ms" => TimeDelta::try_milliseconds(amount),
+                _ => None,
+            }?;
+            total.checked_add(&time_delta)
+        })
+}
+
+/// An ISO-8601 duration made of weeks, days, hours, minutes and seconds. Years and months are
+/// rejected as their length varies.
+fn parse_iso8601_time_delta(arg: &str) -> Option<TimeDelta> {
+    if arg == "P" || arg.ends_with('T') {
+        return None;
+    }
+    let captures = Regex::new(
+        r"^P(?:(\d+)W)?(?:(\d+)D)?(?:T(?:(\d+)H)?(?:(\d+)M)?(?:(\d+)(?:[.,](\d{1,9}))?S)?)?$",
+    )
+    .unwrap()
+    .captures(arg)?;
+    let amount = |index: usize| -> Option<i64> {
+        captures
+            .get(index)
+            .map_or(Some(0), |amount| amount.as_str().parse().ok())
+    };
+    let nanoseconds = captures.get(6).map_or(Some(0), |fraction| {
+        format!("{:0<9}", fraction.as_str()).parse().ok()
+    })?;
+    [
+        TimeDelta::try_weeks(amount(1)?)?,
+        TimeDelta::try_days(amount(2)?)?,
+        TimeDelta::try_hours(amount(3)?)?,
+        TimeDelta::try_minutes(amount(4)?)?,
+        TimeDelta::try_seconds(amount(5)?)?,
+        TimeDelta::nanoseconds(nanoseconds),
+    ]
+    .iter()
+    .try_fold(TimeDelta::zero(), |total, time_delta| {
+        total.checked_add(time_delta)
+    })
+}
+
 pub fn parse_time_delta_range(arg: &str) -> Result<RangeInclusive<Duration>, Error> {
     let arg = arg.trim();
-    match Regex::new(r"^([^.]*\S)[.]{2}(\S[^.]*)$")
+    match Regex::new(r"^(.*\S)[.]{2}(\S.*)$")
         .unwrap()
         .captures(arg)
         .map(|captures| captures.extract())
         .map(|(_, [start, end])| (parse_time_delta(start), parse_time_delta(end)))
         .ok_or(anyhow!("invalid range syntax: {arg}"))?
     {
+        (Ok(start), Ok(end)) if start > end => {
+            bail!("invalid range, start is greater than end: {arg}")
+        }
         (Ok(start), Ok(end)) => Ok(start..=end),
         (Ok(_), Err(_)) => bail!("invalid end duration: {arg}"),
         (Err(sta__rt), Ok(_)) => bail!("invalid start duration: {arg}"),
@@ -42,12 +96,163 @@ pub fn parse_time_delta_range(arg: &str) -> Result<RangeInclusive<Duration>, Err
     }
 }
 
+/// Parse `;` separated rules made of optional months and hours followed by an interval, e.g.
+/// `Oct-Apr 18:00-23:00 1m..2m; 22:00-06:00 1h; 30m`. The rule without months nor hours is the
+/// default interval.
+pub fn parse_repeat_schedule(arg: &str) -> Result<Schedule, Error> {
+    let arg = arg.trim();
+    let mut windows = Vec::new();
+    let mut defaults = Vec::new();
+    for rule in arg.split(';').map(str::trim) {
+        let tokens: Vec<&str> = rule.split_whitespace().collect();
+        let Some((interval, conditions)) = tokens.split_last() else {
+            bail!("empty schedule rule: {arg}");
+        };
+        let interval = if interval.contains("..") {
+            parse_time_delta_range(interval)
+        } else {
+            parse_time_delta(interval).map(|interval| interval..=interval)
+        }
+        .map_err(|_| anyhow!("invalid interval in schedule rule: {rule}"))?;
+        let mut window = ScheduleWindow {
+            months: None,
+            hours: None,
+            interval,
+        };
+        for condition in conditions {
+            match (condition.contains(':'), &window) {
+                (true, ScheduleWindow { hours: None, .. }) => {
+                    window.hours = Some(
+                        parse_bounds(condition, |time| {
+                            NaiveTime::parse_from_str(time, "%H:%M").ok()
+                        })
+                        .ok_or(anyhow!("invalid hours in schedule rule: {rule}"))?,
+                    )
+                }
+                (false, ScheduleWindow { months: None, .. }) => {
+                    window.months = Some(
+                        parse_bounds(condition, |month| month.parse::<Month>().ok())
+                            .ok_or(anyhow!("invalid months in schedule rule: {rule}"))?,
+                    )
+                }
+                _ => bail!("invalid schedule rule: {rule}"),
+            }
+        }
+        if conditions.is_empty() {
+            defaults.push(window.interval);
+        } else {
+            windows.push(window);
+        }
+    }
+    match defaults.as_slice() {
+        [default] => Ok(Schedule {
+            windows,
+            default: default.clone(),
+        }),
+        [] => bail!("missing default interval in schedule: {arg}"),
+        _ => bail!("multiple default intervals in schedule: {arg}"),
+    }
+}
+
+/// Parse `start-end` bounds, or a single value used as both bounds.
+fn parse_bounds<T: Copy, F: Fn(&str) -> Option<T>>(arg: &str, parse: F) -> Option<(T, T)> {
+    match arg.split_once('-') {
+        Some((start, end)) => Some((parse(start)?, parse(end)?)),
+        None => parse(arg).map(|value| (value, value)),
+    }
+}
+
+pub fn parse_jitter(arg: &str) -> Result<Jitter, Error> {
+    match arg.trim() {
+        "none" => Ok(Jitter::None),
+        "full" => Ok(Jitter::Full),
+        "decorrelated" => Ok(Jitter::Decorrelated),
+        arg => bail!("invalid jitter: {arg}, expecting none, full or decorrelated"),
+    }
+}
+
+pub fn parse_timezone(arg: &str) -> Result<Tz, Error> {
+    let arg = arg.trim();
+    arg.parse().map_err(|_| anyhow!("unknown timezone: {arg}"))
+}
+
+/// Address the HTTP server listens on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HttpBind {
+    /// `address:port`, the address being either an IP address or a host name.
+    Tcp(String),
+    /// `unix:/path/to/socket`
+    Unix(PathBuf),
+}
+
+impl Display for HttpBind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpBind::Tcp(address) => write!(f, "{address}"),
+            HttpBind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl HttpBind {
+    /// Whether the server is only reachable from the host: a unix socket or a loopback address.
+    pub fn is_local(&self) -> bool {
+        match self {
+            HttpBind::Tcp(address) => {
+                let host = address
+                    .rsplit_once(':')
+                    .map_or(address.as_str(), |(host, _)| host);
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                host.eq_ignore_ascii_case("localhost")
+                    || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+            }
+            HttpBind::Unix(_) => true,
+        }
+    }
+}
+
+pub fn parse_http_bind(arg: &str) -> Result<HttpBind, Error> {
+    let arg = arg.trim();
+    if let Some(path) = arg.strip_prefix("unix:") {
+        if path.is_empty() {
+            bail!("missing unix socket path: {arg}");
+        }
+        return Ok(HttpBind::Unix(PathBuf::from(path)));
+    }
+    let (address, port) = arg
+        .rsplit_once(':')
+        .ok_or(anyhow!("missing port in bind address: {arg}"))?;
+    if address.is_empty() {
+        bail!("missing address in bind address: {arg}");
+    }
+    port.parse::<u16>()
+        .with_context(|| format!("invalid port in bind address: {arg}"))?;
+    Ok(HttpBind::Tcp(arg.to_string()))
+}
+
+/// Replace the secret by the content of the file when a file is given, ignoring trailing line
+/// breaks.
+pub fn read_secret_file(secret: &mut Option<String>, file: &Option<PathBuf>) -> Result<()> {
+    if let Some(file) = file {
+        let content = fs::read_to_string(file)
+            .with_context(|| format!("Unable to read secret file {}", file.display()))?;
+        *secret = Some(content.trim_end_matches(['\r', '\n']).to_string());
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{ops::RangeInclusive, time::Duration};
+    use std::{fs, ops::RangeInclusive, path::PathBuf, time::Duration};
 
-    use crate::cli::{parse_time_delta, parse_time_delta_range};
-    use chrono::TimeDelta;
+    use crate::{
+        cli::{
+            parse_http_bind, parse_jitter, parse_repeat_schedule, parse_time_delta,
+            parse_time_delta_range, parse_timezone, read_secret_file, HttpBind,
+        },
+        repeat::policy::{Jitter, Schedule, ScheduleWindow},
+    };
+    use chrono::{Month, NaiveTime, TimeDelta};
 
     fn to_std_range(time_delta_range: RangeInclusive<TimeDelta>) -> RangeInclusive<Duration> {
         let start = time_delta_range.start().to_std().unwrap();
@@ -79,6 +284,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn can_parse_compound_time_deltas() {
+        assert_eq!(
+            parse_time_delta("1d12h").unwrap(),
+            TimeDelta::hours(36).to_std().unwrap()
+        );
+        assert_eq!(
+            parse_time_delta("2m30s").unwrap(),
+            TimeDelta::seconds(150).to_std().unwrap()
+        );
+        assert_eq!(
+            parse_time_delta("1h30m15s500ms").unwrap(),
+            TimeDelta::milliseconds(5_415_500).to_std().unwrap()
+        );
+    }
+
+    #[test]
+    fn can_parse_iso8601_time_deltas() {
+        assert_eq!(
+            parse_time_delta("PT90M").unwrap(),
+            TimeDelta::minutes(90).to_std().unwrap()
+        );
+        assert_eq!(
+            parse_time_delta("P1DT12H").unwrap(),
+            TimeDelta::hours(36).to_std().unwrap()
+        );
+        assert_eq!(
+            parse_time_delta("P2W").unwrap(),
+            TimeDelta::days(14).to_std().unwrap()
+        );
+        assert_eq!(
+            parse_time_delta("PT1M0.25S").unwrap(),
+            TimeDelta::milliseconds(60_250).to_std().unwrap()
+        );
+    }
+
     #[test]
     fn can_raise_invalid_format_messages() {
         assert_eq!(
@@ -97,6 +338,26 @@ mod tests {
             parse_time_delta("32 y").unwrap_err().to_string(),
             "invalid duration: 32 y"
         );
+        assert_eq!(
+            parse_time_delta("1h 30m").unwrap_err().to_string(),
+            "invalid duration: 1h 30m"
+        );
+        assert_eq!(
+            parse_time_delta("1h30").unwrap_err().to_string(),
+            "invalid duration: 1h30"
+        );
+        assert_eq!(
+            parse_time_delta("P1M").unwrap_err().to_string(),
+            "invalid duration: P1M"
+        );
+        assert_eq!(
+            parse_time_delta("PT").unwrap_err().to_string(),
+            "invalid duration: PT"
+        );
+        assert_eq!(
+            parse_time_delta("99999999999999d").unwrap_err().to_string(),
+            "invalid duration: 99999999999999d"
+        );
     }
 
     #[test]
@@ -114,8 +375,12 @@ mod tests {
             to_std_range(TimeDelta::seconds(1)..=TimeDelta::hours(5))
         );
         assert_eq!(
-            parse_time_delta_range("25h..1d").unwrap(),
-            to_std_range(TimeDelta::hours(25)..=TimeDelta::hours(24))
+            parse_time_delta_range("1d..24h").unwrap(),
+            to_std_range(TimeDelta::days(1)..=TimeDelta::hours(24))
+        );
+        assert_eq!(
+            parse_time_delta_range("PT7.5S..1m30s").unwrap(),
+            to_std_range(TimeDelta::milliseconds(7500)..=TimeDelta::seconds(90))
         );
     }
 
@@ -141,5 +406,193 @@ mod tests {
             parse_time_delta_range("foo..bar").unwrap_err().to_string(),
             "invalid start and end durations: foo..bar"
         );
+        assert_eq!(
+            parse_time_delta_range("25h..1d").unwrap_err().to_string(),
+            "invalid range, start is greater than end: 25h..1d"
+        );
+    }
+
+    #[test]
+    fn can_parse_repeat_schedules() {
+        assert_eq!(
+            parse_repeat_schedule("8m..12m").unwrap(),
+            Schedule::between(to_std_range(TimeDelta::minutes(8)..=TimeDelta::minutes(12)))
+        );
+        assert_eq!(
+            parse_repeat_schedule(" Oct-Apr 18:00-23:00 1m..2m; 22:00-06:00 1h ; Dec 5m; 30m")
+                .unwrap(),
+            Schedule {
+                windows: vec![
+                    ScheduleWindow {
+                        months: Some((Month::October, Month::April)),
+                        hours: Some((
+                            NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+                            NaiveTime::from_hms_opt(23, 0, 0).unwrap()
+                        )),
+                        interval: to_std_range(TimeDelta::minutes(1)..=TimeDelta::minutes(2)),
+                    },
+                    ScheduleWindow {
+                        months: None,
+                        hours: Some((
+                            NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+                            NaiveTime::from_hms_opt(6, 0, 0).unwrap()
+                        )),
+                        interval: to_std_range(TimeDelta::hours(1)..=TimeDelta::hours(1)),
+                    },
+                    ScheduleWindow {
+                        months: Some((Month::December, Month::December)),
+                        hours: None,
+                        interval: to_std_range(TimeDelta::minutes(5)..=TimeDelta::minutes(5)),
+                    },
+                ],
+                default: to_std_range(TimeDelta::minutes(30)..=TimeDelta::minutes(30)),
+            }
+        );
+    }
+
+    #[test]
+    fn can_raise_invalid_repeat_schedules() {
+        assert_eq!(
+            parse_repeat_schedule("Oct-Apr 1m; 30m; 1h")
+                .unwrap_err()
+                .to_string(),
+            "multiple default intervals in schedule: Oct-Apr 1m; 30m; 1h"
+        );
+        assert_eq!(
+            parse_repeat_schedule("Oct-Apr 1m").unwrap_err().to_string(),
+            "missing default interval in schedule: Oct-Apr 1m"
+        );
+        assert_eq!(
+            parse_repeat_schedule("Oct-Foo 1m; 30m")
+                .unwrap_err()
+                .to_string(),
+            "invalid months in schedule rule: Oct-Foo 1m"
+        );
+        assert_eq!(
+            parse_repeat_schedule("18:00-25:00 1m; 30m")
+                .unwrap_err()
+                .to_string(),
+            "invalid hours in schedule rule: 18:00-25:00 1m"
+        );
+        assert_eq!(
+            parse_repeat_schedule("Oct Dec 1m; 30m")
+                .unwrap_err()
+                .to_string(),
+            "invalid schedule rule: Oct Dec 1m"
+        );
+        assert_eq!(
+            parse_repeat_schedule("Oct 2m..1m; 30m")
+                .unwrap_err()
+                .to_string(),
+            "invalid interval in schedule rule: Oct 2m..1m"
+        );
+        assert_eq!(
+            parse_repeat_schedule("30m;").unwrap_err().to_string(),
+            "empty schedule rule: 30m;"
+        );
+    }
+
+    #[test]
+    fn can_parse_jitters() {
+        assert_eq!(parse_jitter("none").unwrap(), Jitter::None);
+        assert_eq!(parse_jitter(" full ").unwrap(), Jitter::Full);
+        assert_eq!(parse_jitter("decorrelated").unwrap(), Jitter::Decorrelated);
+        assert_eq!(
+            parse_jitter("equal").unwrap_err().to_string(),
+            "invalid jitter: equal, expecting none, full or decorrelated"
+        );
+    }
+
+    #[test]
+    fn can_parse_timezones() {
+        assert_eq!(
+            parse_timezone("Europe/Paris").unwrap(),
+            chrono_tz::Europe::Paris
+        );
+        assert_eq!(
+            parse_timezone("Mars/Olympus").unwrap_err().to_string(),
+            "unknown timezone: Mars/Olympus"
+        );
+    }
+
+    #[test]
+    fn can_parse_http_bind_addresses() {
+        assert_eq!(
+            parse_http_bind("0.0.0.0:8080").unwrap(),
+            HttpBind::Tcp("0.0.0.0:8080".to_string())
+        );
+        assert_eq!(
+            parse_http_bind(" [::1]:9000 ").unwrap(),
+            HttpBind::Tcp("[::1]:9000".to_string())
+        );
+        assert_eq!(
+            parse_http_bind("localhost:80").unwrap(),
+            HttpBind::Tcp("localhost:80".to_string())
+        );
+        assert_eq!(
+            parse_http_bind("unix:/run/hass-mqtt-bridge.sock").unwrap(),
+            HttpBind::Unix(PathBuf::from("/run/hass-mqtt-bridge.sock"))
+        );
+    }
+
+    #[test]
+    fn can_tell_local_http_bind_addresses() {
+        assert!(parse_http_bind("127.0.0.1:8080").unwrap().is_local());
+        assert!(parse_http_bind("[::1]:8080").unwrap().is_local());
+        assert!(parse_http_bind("localhost:8080").unwrap().is_local());
+        assert!(parse_http_bind("unix:/run/hass-mqtt-bridge.sock")
+            .unwrap()
+            .is_local());
+        assert!(!parse_http_bind("0.0.0.0:8080").unwrap().is_local());
+        assert!(!parse_http_bind("[::]:8080").unwrap().is_local());
+        assert!(!parse_http_bind("192.168.1.10:8080").unwrap().is_local());
+        assert!(!parse_http_bind("bridge.example.com:8080")
+            .unwrap()
+            .is_local());
+    }
+
+    #[test]
+    fn can_raise_invalid_http_bind_addresses() {
+        assert_eq!(
+            parse_http_bind("localhost").unwrap_err().to_string(),
+            "missing port in bind address: localhost"
+        );
+        assert_eq!(
+            parse_http_bind(":8080").unwrap_err().to_string(),
+            "missing address in bind address: :8080"
+        );
+        assert_eq!(
+            parse_http_bind("localhost:http").unwrap_err().to_string(),
+            "invalid port in bind address: localhost:http"
+        );
+        assert_eq!(
+            parse_http_bind("unix:").unwrap_err().to_string(),
+            "missing unix socket path: unix:"
+        );
+    }
+
+    #[test]
+    fn can_read_secret_files() {
+        let file = std::env::temp_dir().join(format!(
+            "hass-mqtt-bridge-secret-test-{}",
+            std::process::id()
+        ));
+        fs::write(&file, "s3cr3t\n").unwrap();
+
+        let mut secret = None;
+        read_secret_file(&mut secret, &Some(file.clone())).unwrap();
+        assert_eq!(secret, Some("s3cr3t".to_string()));
+
+        let mut secret = Some("unchanged".to_string());
+        read_secret_file(&mut secret, &None).unwrap();
+        assert_eq!(secret, Some("unchanged".to_string()));
+
+        fs::remove_file(&file).unwrap();
+        assert_eq!(
+            read_secret_file(&mut secret, &Some(file.clone()))
+                .unwrap_err()
+                .to_string(),
+            format!("Unable to read secret file {}", file.display())
+        );
     }
 }
@@ -0,0 +1,568 @@
+use anyhow::{bail, Context, Result};
+use clap::{error::ErrorKind, parser::ValueSource, Arg, ArgMatches, Args, Command, Parser};
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, error::Error, ffi::OsString, fs, path::Path};
+
+/// Name of the argument holding the configuration file path.
+const CONFIG_ARG_ID: &str = "config";
+
+/// Configuration file sections and the prefix of the command line arguments they map to.
+const SECTIONS: [(&str, &str); 5] = [
+    ("http", "http"),
+    ("mqtt", "mqtt"),
+    ("home_assistant", "ha"),
+    ("rika", "rika"),
+    ("somfy", "somfy"),
+];
+
+/// Sections accepting named accounts, e.g. `[rika.accounts.home]`, whose settings are the ones of
+/// the section itself.
+const ACCOUNT_SECTIONS: [&str; 2] = ["rika", "somfy"];
+
+const ACCOUNTS_KEY: &str = "accounts";
+
+/// Settings by command line argument id.
+type Settings = BTreeMap<String, Setting>;
+
+/// Command line arguments and named accounts from the configuration file.
+pub struct Configuration<C> {
+    pub cli: C,
+    /// Account settings by section, then by account name
+    accounts: BTreeMap<String, BTreeMap<String, Settings>>,
+}
+
+impl<C> Configuration<C> {
+    /// Named accounts of a section. Neither environment variables nor flags apply to them, only
+    /// their own settings and the arguments default values.
+    pub fn accounts<A: Args>(&self, section: &str) -> Result<Vec<(String, A)>> {
+        self.accounts
+            .get(section)
+            .into_iter()
+            .flatten()
+            .map(|(name, settings)| {
+                let command = A::augment_args(Command::new(format!("{section}.{ACCOUNTS_KEY}")))
+                    .mut_args(|arg| arg.env(None::<&str>));
+                let args: Vec<String> = settings
+                    .iter()
+                    .filter_map(|(arg_id, setting)| as_flag(&command, arg_id, setting))
+                    .collect();
+                let matches = command
+                    .try_get_matches_from([name.clone()].into_iter().chain(args))
+                    .with_context(|| format!("Invalid account {section}.{ACCOUNTS_KEY}.{name}"))?;
+                Ok((name.clone(), A::from_arg_matches(&matches)?))
+            })
+            .collect()
+    }
+}
+
+/// Parse command line arguments, using settings from the optional configuration file as fallback
+/// values for both environment variables and flags.
+///
+/// Configuration file settings are passed to clap as flags, unless the argument is already given
+/// by a flag or an environment variable, hence the precedence: file < environment variables <
+/// flags.
+pub fn parse<C: Parser>() -> Result<Configuration<C>> {
+    read_configuration(|args| Ok(C::parse_from(args)))
+}
+
+/// Same as [parse] but invalid arguments are reported as errors instead of exiting, to read the
+/// configuration again once running.
+pub fn reparse<C: Parser>() -> Result<Configuration<C>> {
+    read_configuration(|args| Ok(C::try_parse_from(args)?))
+}
+
+fn read_configuration<C: Parser, F: FnOnce(Vec<OsString>) -> Result<C>>(
+    parse_cli: F,
+) -> Result<Configuration<C>> {
+    let command = C::command();
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok();
+    let mut accounts = BTreeMap::new();
+    let mut file_args = Vec::new();
+    if let Some(path) = matches.as_ref().and_then(config_file_path) {
+        let document = read_document(&path)?;
+        validate(&command, &document)
+            .with_context(|| format!("Invalid configuration file {path}"))?;
+        file_args = as_fallback_flags(&command, matches.as_ref(), &document.settings);
+        accounts = document.accounts;
+    }
+    // the program name, then the settings of the file, then the actual arguments
+    let args = args
+        .iter()
+        .take(1)
+        .cloned()
+        .chain(file_args.into_iter().map(OsString::from))
+        .chain(args.iter().skip(1).cloned())
+        .collect();
+    Ok(Configuration {
+        cli: parse_cli(args)?,
+        accounts,
+    })
+}
+
+fn config_file_path(matches: &ArgMatches) -> Option<String> {
+    matches
+        .try_get_raw(CONFIG_ARG_ID)
+        .ok()
+        .flatten()
+        .and_then(|mut values| values.next())
+        .map(|value| value.to_string_lossy().to_string())
+}
+
+/// A setting from the configuration file.
+#[derive(Debug, PartialEq)]
+struct Setting {
+    /// Setting name as written in the file, e.g. `rika.username`
+    key: String,
+    value: Option<String>,
+}
+
+/// Configuration file content.
+#[derive(Debug, Default, PartialEq)]
+struct Document {
+    settings: Settings,
+    /// Account settings by section, then by account name
+    accounts: BTreeMap<String, BTreeMap<String, Settings>>,
+    /// Structural errors found while flattening the document
+    errors: Vec<String>,
+}
+
+fn read_document<P: AsRef<Path>>(path: P) -> Result<Document> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read configuration file {}", path.display()))?;
+    let document: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content)
+            .with_context(|| format!("Invalid TOML configuration file {}", path.display()))?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid YAML configuration file {}", path.display()))?,
+        _ => bail!(
+            "Unsupported configuration file format {}, expecting .toml, .yaml or .yml",
+            path.display()
+        ),
+    };
+    match document {
+        Value::Object(root) => Ok(flatten(root)),
+        Value::Null => Ok(Document::default()),
+        _ => bail!(
+            "Invalid configuration file {}, expecting a map of settings",
+            path.display()
+        ),
+    }
+}
+
+/// Flatten the configuration document into settings indexed by their command line argument id.
+fn flatten(root: Map<String, Value>) -> Document {
+    let mut document = Document::default();
+    for (name, value) in root {
+        match (value, SECTIONS.iter().find(|(section, _)| *section == name)) {
+            (Value::Object(section), Some((_, arg_prefix))) => {
+                for (setting_name, value) in section {
+                    match value {
+                        Value::Object(accounts)
+                            if setting_name == ACCOUNTS_KEY
+                                && ACCOUNT_SECTIONS.contains(&name.as_str()) =>
+                        {
+                            flatten_accounts(&mut document, &name, arg_prefix, accounts)
+                        }
+                        value => {
+                            document.settings.insert(
+                                format!("{arg_prefix}_{setting_name}"),
+                                Setting {
+                                    key: format!("{name}.{setting_name}"),
+                                    value: scalar(value),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            (value, _) => {
+                document.settings.insert(
+                    name.clone(),
+                    Setting {
+                        key: name,
+                        value: scalar(value),
+                    },
+                );
+            }
+        }
+    }
+    document
+}
+
+fn flatten_accounts(
+    document: &mut Document,
+    section: &str,
+    arg_prefix: &str,
+    accounts: Map<String, Value>,
+) {
+    for (account_name, account) in accounts {
+        let key_prefix = format!("{section}.{ACCOUNTS_KEY}.{account_name}");
+        let Value::Object(account) = account else {
+            document
+                .errors
+                .push(format!("- {key_prefix}: expecting a section"));
+            continue;
+        };
+        let settings = account
+            .into_iter()
+            .map(|(setting_name, value)| {
+                let setting = Setting {
+                    key: format!("{key_prefix}.{setting_name}"),
+                    value: scalar(value),
+                };
+                (format!("{arg_prefix}_{setting_name}"), setting)
+            })
+            .collect();
+        document
+            .accounts
+            .entry(section.to_string())
+            .or_default()
+            .insert(account_name, settings);
+    }
+}
+
+/// A single value as a string, [None] for lists and sections.
+fn scalar(value: Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// Check every setting against the matching command line argument and report all invalid ones at
+/// once.
+fn validate(command: &Command, document: &Document) -> Result<()> {
+    let account_settings = document
+        .accounts
+        .values()
+        .flat_map(BTreeMap::values)
+        .flatten();
+    let mut errors = document.errors.clone();
+    errors.extend(document.settings.iter().chain(account_settings).filter_map(
+        |(arg_id, setting)| {
+            validate_setting(command, arg_id, setting)
+                .err()
+                .map(|reason| format!("- {}: {reason}", setting.key))
+        },
+    ));
+    if !errors.is_empty() {
+        bail!("{} invalid settings:\n{}", errors.len(), errors.join("\n"));
+    }
+    Ok(())
+}
+
+fn validate_setting(command: &Command, arg_id: &str, setting: &Setting) -> Result<(), String> {
+    let arg = find_arg(command, arg_id).ok_or("unknown setting".to_string())?;
+    let value = setting
+        .value
+        .as_ref()
+        .ok_or("expecting a single value".to_string())?;
+    if !arg.get_action().takes_values() {
+        return match value.as_str() {
+            "true" | "false" => Ok(()),
+            _ => Err(format!("expecting a boolean, got {value:?}")),
+        };
+    }
+    let long = arg.get_long().ok_or("unknown setting".to_string())?;
+    let result = command
+        .clone()
+        .mut_args(|arg| arg.required(false).env(None::<&str>))
+        .try_get_matches_from([command.get_name().to_string(), format!("--{long}={value}")]);
+    match result {
+        Err(error) if error.kind() != ErrorKind::MissingRequiredArgument => Err(error
+            .source()
+            .map(|source| source.to_string())
+            .unwrap_or_else(|| format!("{}: {value:?}", error.kind()))),
+        _ => Ok(()),
+    }
+}
+
+fn find_arg<'a>(command: &'a Command, arg_id: &str) -> Option<&'a Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| arg.get_id() != CONFIG_ARG_ID)
+        .filter(|arg| arg.get_env().is_some())
+        .find(|arg| arg.get_id() == arg_id)
+}
+
+/// The setting as a command line flag, [None] for disabled boolean flags.
+fn as_flag(command: &Command, arg_id: &str, setting: &Setting) -> Option<String> {
+    let arg = command.get_arguments().find(|arg| arg.get_id() == arg_id)?;
+    let long = arg.get_long()?;
+    match (arg.get_action().takes_values(), setting.value.as_deref()?) {
+        (false, "true") => Some(format!("--{long}")),
+        (false, _) => None,
+        (true, value) => Some(format!("--{long}={value}")),
+    }
+}
+
+/// The settings as command line flags, except the ones already given by a flag or an environment
+/// variable, which take precedence over the configuration file.
+fn as_fallback_flags(
+    command: &Command,
+    matches: Option<&ArgMatches>,
+    settings: &Settings,
+) -> Vec<String> {
+    settings
+        .iter()
+        .filter_map(|(arg_id, setting)| {
+            let flag = as_flag(command, arg_id, setting)?;
+            match matches.and_then(|matches| matches.value_source(arg_id)) {
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable) => None,
+                _ => Some(flag),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::{Args, CommandFactory, Parser};
+    use serde_json::{Map, Value};
+    use url::Url;
+
+    use crate::config::{as_fallback_flags, flatten, validate, Configuration, Setting};
+
+    #[allow(dead_code)]
+    #[derive(Parser)]
+    struct TestCli {
+        #[clap(long, env)]
+        config: Option<String>,
+
+        #[clap(long, env)]
+        mqtt_username: String,
+
+        #[clap(long, env, value_parser = crate::cli::parse_time_delta, default_value = "30m")]
+        readiness_max_age: Duration,
+
+        #[clap(long, env)]
+        http_disabled: bool,
+
+        #[clap(flatten)]
+        rika: TestRikaArgs,
+    }
+
+    #[derive(Args, Debug, PartialEq)]
+    struct TestRikaArgs {
+        #[clap(long, env)]
+        rika_baseurl: Option<Url>,
+
+        #[clap(long, env)]
+        rika_username: Option<String>,
+    }
+
+    fn setting(key: &str, value: &str) -> Setting {
+        Setting {
+            key: key.to_string(),
+            value: Some(value.to_string()),
+        }
+    }
+
+    #[test]
+    fn can_flatten_toml_and_yaml_documents() {
+        let toml: Map<String, Value> = toml::from_str(
+            r#"
+            readiness_max_age = "1h"
+            [mqtt]
+            username = "bridge"
+            [home_assistant]
+            discovery_prefix = "ha"
+            [http]
+            disabled = true
+            [rika.accounts.home]
+            username = "me"
+            "#,
+        )
+        .unwrap();
+        let yaml: Map<String, Value> = serde_yaml::from_str(
+            r#"
+            readiness_max_age: 1h
+            mqtt:
+              username: bridge
+            home_assistant:
+              discovery_prefix: ha
+            http:
+              disabled: true
+            rika:
+              accounts:
+                home:
+                  username: me
+            "#,
+        )
+        .unwrap();
+
+        let document = flatten(toml);
+        assert_eq!(
+            document.settings["readiness_max_age"],
+            setting("readiness_max_age", "1h")
+        );
+        assert_eq!(
+            document.settings["mqtt_username"],
+            setting("mqtt.username", "bridge")
+        );
+        assert_eq!(
+            document.settings["ha_discovery_prefix"],
+            setting("home_assistant.discovery_prefix", "ha")
+        );
+        assert_eq!(
+            document.settings["http_disabled"],
+            setting("http.disabled", "true")
+        );
+        assert_eq!(
+            document.accounts["rika"]["home"]["rika_username"],
+            setting("rika.accounts.home.username", "me")
+        );
+        assert_eq!(document, flatten(yaml));
+    }
+
+    #[test]
+    fn can_accept_valid_settings() {
+        let document = flatten(
+            toml::from_str(
+                r#"
+                readiness_max_age = "1h"
+                [mqtt]
+                username = "bridge"
+                [http]
+                disabled = false
+                [rika]
+                baseurl = "https://www.rika-firenet.com"
+                [rika.accounts.home]
+                username = "me"
+                "#,
+            )
+            .unwrap(),
+        );
+        validate(&TestCli::command(), &document).unwrap();
+    }
+
+    #[test]
+    fn can_report_every_invalid_setting() {
+        let document = flatten(
+            toml::from_str(
+                r#"
+                readiness_max_age = "1y"
+                config = "other.toml"
+                [mqtt]
+                username = ["a", "b"]
+                brokr_url = "mqtt://localhost"
+                [http]
+                disabled = "maybe"
+                [rika]
+                baseurl = "not an url"
+                [rika.accounts]
+                cabin = "cabin"
+                [rika.accounts.home]
+                baseurl = "not an url either"
+                [http.accounts.home]
+                bind = "localhost:8080"
+                "#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            validate(&TestCli::command(), &document)
+                .unwrap_err()
+                .to_string(),
+            [
+                "9 invalid settings:",
+                "- rika.accounts.cabin: expecting a section",
+                "- config: unknown setting",
+                "- http.accounts: unknown setting",
+                "- http.disabled: expecting a boolean, got \"maybe\"",
+                "- mqtt.brokr_url: unknown setting",
+                "- mqtt.username: expecting a single value",
+                "- readiness_max_age: invalid duration: 1y",
+                "- rika.baseurl: relative URL without a base",
+                "- rika.accounts.home.baseurl: relative URL without a base",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn can_give_precedence_to_flags_over_settings() {
+        let document = flatten(
+            toml::from_str(
+                r#"
+                readiness_max_age = "1h"
+                [mqtt]
+                username = "file"
+                [http]
+                disabled = true
+                "#,
+            )
+            .unwrap(),
+        );
+        let command = TestCli::command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(["test", "--mqtt-username=cli"])
+            .unwrap();
+
+        let flags = as_fallback_flags(&command, Some(&matches), &document.settings);
+        assert_eq!(flags, vec!["--http-disabled", "--readiness-max-age=1h"]);
+        let cli = TestCli::try_parse_from(
+            ["test"]
+                .into_iter()
+                .chain(flags.iter().map(String::as_str))
+                .chain(["--mqtt-username=cli"]),
+        )
+        .unwrap();
+        assert_eq!(cli.mqtt_username, "cli");
+        assert_eq!(cli.readiness_max_age, Duration::from_secs(3600));
+        assert!(cli.http_disabled);
+    }
+
+    #[test]
+    fn can_parse_named_accounts() {
+        let document = flatten(
+            toml::from_str(
+                r#"
+                [rika.accounts.home]
+                username = "me"
+                [rika.accounts.cabin]
+                baseurl = "https://cabin.local"
+                "#,
+            )
+            .unwrap(),
+        );
+        let configuration = Configuration {
+            cli: (),
+            accounts: document.accounts,
+        };
+        assert_eq!(
+            configuration.accounts::<TestRikaArgs>("rika").unwrap(),
+            vec![
+                (
+                    "cabin".to_string(),
+                    TestRikaArgs {
+                        rika_baseurl: Some(Url::parse("https://cabin.local").unwrap()),
+                        rika_username: None,
+                    }
+                ),
+                (
+                    "home".to_string(),
+                    TestRikaArgs {
+                        rika_baseurl: None,
+                        rika_username: Some("me".to_string()),
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            configuration.accounts::<TestRikaArgs>("somfy").unwrap(),
+            vec![]
+        );
+    }
+}
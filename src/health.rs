@@ -0,0 +1,220 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::metrics;
+
+/// Shared view of the bridge health: MQTT connection state and outcome of every repeated task.
+#[derive(Clone)]
+pub struct HealthRegistry {
+    readiness_max_age: Duration,
+    state: Arc<Mutex<HealthState>>,
+}
+
+#[derive(Default, Serialize)]
+struct HealthState {
+    mqtt_connected: bool,
+    /// No provider account is configured, hence no task is expected to succeed
+    no_provider_configured: bool,
+    tasks: BTreeMap<String, TaskHealth>,
+}
+
+#[derive(Default, Clone, Serialize)]
+struct TaskHealth {
+    last_success: Option<DateTime<Utc>>,
+    last_failure: Option<DateTime<Utc>>,
+    consecutive_failures: u32,
+    backoff_delay_seconds: Option<u64>,
+    /// Stopped because of a permanent error
+    aborted: bool,
+}
+
+impl HealthRegistry {
+    /// A task success is considered recent for readiness checks when younger than
+    /// `readiness_max_age`.
+    pub fn new(readiness_max_age: Duration) -> Self {
+        Self {
+            readiness_max_age,
+            state: Arc::new(Mutex::new(HealthState::default())),
+        }
+    }
+
+    /// Registers a task and returns a handle to report its executions.
+    pub fn task<S: Into<String>>(&self, name: S) -> TaskMonitor {
+        let name = name.into();
+        self.state
+            .lock()
+            .unwrap()
+            .tasks
+            .entry(name.clone())
+            .or_default();
+        TaskMonitor {
+            name,
+            registry: self.clone(),
+        }
+    }
+
+    pub fn remove_task(&self, name: &str) {
+        self.state.lock().unwrap().tasks.remove(name);
+        metrics::remove_task(name);
+    }
+
+    pub fn set_mqtt_connected(&self, connected: bool) {
+        self.state.lock().unwrap().mqtt_connected = connected;
+    }
+
+    pub fn set_providers_configured(&self, configured: bool) {
+        self.state.lock().unwrap().no_provider_configured = !configured;
+    }
+
+    /// Ready when connected to the MQTT broker and at least one task succeeded recently, unless
+    /// no provider is configured at all.
+    pub fn is_ready(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let now = Utc::now();
+        let recent_success = state.no_provider_configured
+            || state.tasks.values().any(|task| {
+                task.last_success.is_some_and(|last_success| {
+                    (now - last_success)
+                        .to_std()
+                        .ok()
+                        .is_none_or(|age| age <= self.readiness_max_age)
+                })
+            });
+        state.mqtt_connected && recent_success
+    }
+
+    fn update_task<F: FnOnce(&mut TaskHealth)>(&self, name: &str, update: F) {
+        let mut state = self.state.lock().unwrap();
+        update(state.tasks.entry(name.to_string()).or_default());
+    }
+}
+
+/// Reports executions of a task to the [HealthRegistry].
+#[derive(Clone)]
+pub struct TaskMonitor {
+    name: String,
+    registry: HealthRegistry,
+}
+
+impl TaskMonitor {
+    pub fn success(&self) {
+        self.registry.update_task(&self.name, |task| {
+            task.last_success = Some(Utc::now());
+            task.consecutive_failures = 0;
+            task.backoff_delay_seconds = None;
+            task.aborted = false;
+        });
+        metrics::set_task_backoff_delay(&self.name, Duration::ZERO);
+    }
+
+    pub fn failure(&self, backoff_delay: Duration) {
+        self.registry.update_task(&self.name, |task| {
+            task.last_failure = Some(Utc::now());
+            task.consecutive_failures += 1;
+            task.backoff_delay_seconds = Some(backoff_delay.as_secs());
+        });
+        metrics::set_task_backoff_delay(&self.name, backoff_delay);
+    }
+
+    /// Reports a failure the task won't be retried after.
+    pub fn abort(&self) {
+        self.registry.update_task(&self.name, |task| {
+            task.last_failure = Some(Utc::now());
+            task.consecutive_failures += 1;
+            task.backoff_delay_seconds = None;
+            task.aborted = true;
+        });
+        metrics::set_task_backoff_delay(&self.name, Duration::ZERO);
+    }
+
+    /// Unregisters the task, once it is no longer executed.
+    pub fn remove(&self) {
+        self.registry.remove_task(&self.name);
+    }
+}
+
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("OK")
+}
+
+#[get("/readyz")]
+async fn readyz(health: web::Data<HealthRegistry>) -> impl Responder {
+    if health.is_ready() {
+        HttpResponse::Ok().body("OK")
+    } else {
+        HttpResponse::ServiceUnavailable().body("NOT READY")
+    }
+}
+
+#[get("/status")]
+async fn status(health: web::Data<HealthRegistry>) -> impl Responder {
+    HttpResponse::Ok().json(&*health.state.lock().unwrap())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz).service(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::health::HealthRegistry;
+
+    #[test]
+    fn is_not_ready_until_mqtt_is_connected() {
+        let health = HealthRegistry::new(Duration::from_secs(60));
+        health.set_providers_configured(false);
+        assert!(!health.is_ready(), "not ready before MQTT connection");
+
+        health.set_mqtt_connected(true);
+        assert!(health.is_ready(), "ready without any provider");
+
+        health.set_mqtt_connected(false);
+        assert!(!health.is_ready(), "not ready after MQTT disconnection");
+    }
+
+    #[test]
+    fn is_not_ready_before_any_scrape() {
+        let health = HealthRegistry::new(Duration::from_secs(60));
+        health.set_mqtt_connected(true);
+        assert!(!health.is_ready(), "not ready before providers are started");
+
+        health.set_providers_configured(true);
+        assert!(!health.is_ready(), "not ready before tasks are registered");
+
+        health.task("task");
+        assert!(!health.is_ready(), "not ready before any task execution");
+    }
+
+    #[test]
+    fn is_ready_once_a_task_succeeded() {
+        let health = HealthRegistry::new(Duration::from_secs(60));
+        health.set_mqtt_connected(true);
+        let first_task = health.task("first");
+        let second_task = health.task("second");
+        assert!(!health.is_ready(), "not ready before any task success");
+
+        first_task.failure(Duration::from_secs(5));
+        assert!(!health.is_ready(), "not ready after a failure");
+
+        second_task.success();
+        assert!(health.is_ready(), "ready after one task success");
+    }
+
+    #[test]
+    fn is_not_ready_when_last_success_is_too_old() {
+        let health = HealthRegistry::new(Duration::ZERO);
+        health.set_mqtt_connected(true);
+        health.task("task").success();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(!health.is_ready(), "success older than max age is ignored");
+    }
+}
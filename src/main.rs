@@ -1,31 +1,68 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::iter;
 use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::pin::pin;
 use std::time::Duration;
 
-use actix::Actor;
-use actix_web::{middleware::Logger, App, HttpServer};
-use anyhow::Result;
-use clap::Parser;
-use log::{debug, info};
+use actix::{Actor, Addr};
+use actix_web::{middleware::Logger, web, App, HttpServer};
+use anyhow::{bail, Context, Result};
+use chrono_tz::Tz;
+use circuit_breaker::{CircuitBreaker, CircuitBreakerConfiguration};
+use clap::{ArgGroup, Args, Parser};
+use cli::HttpBind;
+use config::Configuration;
+use futures::future::{join_all, select, Either};
+use futures::StreamExt;
+use health::HealthRegistry;
+use log::{debug, error, info, warn};
 use misc::app_infos;
 use misc::SuffixStrip;
 use mqtt::MqttActor;
+use mqtt::MqttActorConfiguration;
+use mqtt::Topics;
+use reload::{ReloadRequest, ReloadTrigger};
+use repeat::policy::{Jitter, Schedule};
 use rika::StoveDiscoveryActor;
 use rika::StoveDiscoveryActorConfiguration;
 use rika_firenet_client::RikaFirenetClientBuilder;
+use shutdown::{Shutdown, Stop};
+use somfy_api::SomfyApiClientBuilder;
 use somfy_protect::SomfyActor;
-use somfy_protect_client::client::SomfyProtectClientBuilder;
+use somfy_protect::SomfyActorConfiguration;
+use state::StateStore;
+use tls::TlsOptions;
 use url::Url;
 
+mod circuit_breaker;
 mod cli;
+mod config;
+mod health;
+mod metrics;
 mod misc;
 mod mqtt;
+mod problem;
+mod rate_limit;
+mod reload;
 mod repeat;
 mod rika;
+mod shutdown;
+mod somfy_api;
 mod somfy_protect;
+mod state;
+mod tls;
 
 #[derive(Parser)]
+#[clap(group(ArgGroup::new("mqtt_password_input").args(["mqtt_password", "mqtt_password_file"]).required(true)))]
 struct Cli {
-    /// MQTT broker URL
+    /// Configuration file (.toml, .yaml or .yml), its settings are overridden by environment
+    /// variables and flags
+    #[clap(long, env)]
+    config: Option<PathBuf>,
+
+    /// MQTT broker URL, either mqtt://, mqtts://, ws:// or wss://
     #[clap(long, env, default_value_t = Url::parse("mqtt://localhost:1883").expect("A valid broker URL example"))]
     mqtt_broker_url: Url,
 
@@ -35,36 +72,111 @@ struct Cli {
 
     /// MQTT password
     #[clap(long, env)]
-    mqtt_password: String,
+    mqtt_password: Option<String>,
 
-    /// Rika API base URL
+    /// File containing the MQTT password
     #[clap(long, env)]
-    rika_baseurl: Option<Url>,
+    mqtt_password_file: Option<PathBuf>,
 
-    /// Rika username
-    #[clap(long, env, requires = "rika_password")]
-    rika_username: Option<String>,
+    /// PEM file of certificate authorities trusted to verify the MQTT broker (mqtts:// only)
+    #[clap(long, env)]
+    mqtt_ca_file: Option<PathBuf>,
 
-    /// Rika password
-    #[clap(long, env, requires = "rika_username")]
-    rika_password: Option<String>,
+    /// PEM client certificate file for MQTT mutual TLS authentication
+    #[clap(long, env, requires = "mqtt_client_key_file")]
+    mqtt_client_cert_file: Option<PathBuf>,
 
-    /// Rika stove discovery scan interval
-    #[clap(long, env, value_parser = cli::parse_time_delta_range, default_value = "6d..8d")]
-    rika_stove_discovery_repeat_interval: RangeInclusive<Duration>,
+    /// PEM client private key file for MQTT mutual TLS authentication
+    #[clap(long, env, requires = "mqtt_client_cert_file")]
+    mqtt_client_key_file: Option<PathBuf>,
 
-    /// Rika stove discovery exponential backoff ceil
-    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "8h")]
-    rika_stove_discovery_backoff_ceil: Duration,
+    /// Name expected in the MQTT broker certificate when it differs from the broker URL host
+    #[clap(long, env)]
+    mqtt_tls_server_name: Option<String>,
 
-    /// Rika stove status update interval
-    #[clap(long, env, value_parser = cli::parse_time_delta_range, default_value = "8m..12m")]
-    rika_stove_status_repeat_interval: RangeInclusive<Duration>,
+    /// Home Assistant MQTT discovery prefix
+    #[clap(long, env, default_value = "homeassistant")]
+    ha_discovery_prefix: String,
 
-    /// Rika stove status update exponential backoff ceil
-    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "8h")]
-    rika_stove_status_backoff_ceil: Duration,
+    /// Topic where Home Assistant publishes its online/offline status
+    #[clap(long, env, default_value = "homeassistant/status")]
+    ha_status_topic: String,
+
+    /// Namespace prepended to every state and command topic managed by the bridge
+    #[clap(long, env)]
+    mqtt_topic_prefix: Option<String>,
+
+    /// Number of consecutive scrapes a device must be missing from before its entities are
+    /// removed from Home Assistant, 0 never removes them
+    #[clap(long, env, default_value_t = 3)]
+    missing_device_removal_threshold: u32,
+
+    /// HTTP server listen address, either address:port or unix:/path/to/socket. Admin endpoints
+    /// are only served on loopback addresses and unix sockets.
+    #[clap(long, env, value_parser = cli::parse_http_bind, default_value = "127.0.0.1:8080")]
+    http_bind: HttpBind,
 
+    /// Do not start the HTTP server (health, status and metrics endpoints)
+    #[clap(long, env)]
+    http_disabled: bool,
+
+    /// Randomization of the exponential backoff delays: none, full or decorrelated
+    #[clap(long, env, value_parser = cli::parse_jitter, default_value = "none")]
+    backoff_jitter: Jitter,
+
+    /// Timezone of the repeat schedules months and hours, the system one by default
+    #[clap(long, env, value_parser = cli::parse_timezone)]
+    timezone: Option<Tz>,
+
+    /// Number of consecutive failed API calls pausing every call to the provider
+    #[clap(long, env, default_value_t = 5)]
+    circuit_breaker_failure_threshold: u32,
+
+    /// Duration of the pause once the circuit breaker of a provider opened, then a single call
+    /// probes the API
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "5m")]
+    circuit_breaker_open_duration: Duration,
+
+    /// File where the schedule of the repeated tasks is saved, to resume it after a restart
+    #[clap(long, env)]
+    state_file: Option<PathBuf>,
+
+    /// Minimum duration between the first API calls of two consecutive runs, when restarted in a
+    /// loop
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "1m")]
+    min_restart_interval: Duration,
+
+    /// Maximum age of the last successful scrape for the bridge to be reported ready
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "30m")]
+    readiness_max_age: Duration,
+
+    /// Maximum duration given to flush pending commands and disconnect from the MQTT broker on
+    /// SIGTERM or SIGINT
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "10s")]
+    shutdown_timeout: Duration,
+
+    #[clap(flatten)]
+    rika: RikaArgs,
+
+    /// Named Rika accounts from the configuration file
+    #[clap(skip)]
+    rika_accounts: Vec<(String, RikaArgs)>,
+
+    #[clap(flatten)]
+    somfy: SomfyArgs,
+
+    /// Named Somfy Protect accounts from the configuration file
+    #[clap(skip)]
+    somfy_accounts: Vec<(String, SomfyArgs)>,
+}
+
+/// Somfy Protect account settings, shared by the command line and the named accounts of the
+/// configuration file.
+#[derive(Args, Clone)]
+#[clap(group(ArgGroup::new("somfy_client_secret_input").args(["somfy_client_secret", "somfy_client_secret_file"])))]
+#[clap(group(ArgGroup::new("somfy_password_input").args(["somfy_password", "somfy_password_file"])))]
+#[clap(group(ArgGroup::new("somfy_token_key_input").args(["somfy_token_key", "somfy_token_key_file"])))]
+struct SomfyArgs {
     /// Somfy Protect API base URL
     #[clap(long, env)]
     somfy_api_baseurl: Option<Url>,
@@ -77,9 +189,9 @@ struct Cli {
     #[clap(
         long,
         env,
-        requires = "somfy_client_secret",
+        requires = "somfy_client_secret_input",
         requires = "somfy_username",
-        requires = "somfy_password"
+        requires = "somfy_password_input"
     )]
     somfy_client_id: Option<String>,
 
@@ -89,17 +201,27 @@ struct Cli {
         env,
         requires = "somfy_client_id",
         requires = "somfy_username",
-        requires = "somfy_password"
+        requires = "somfy_password_input"
     )]
     somfy_client_secret: Option<String>,
 
+    /// File containing the Somfy Protect API OAuth Client secret
+    #[clap(
+        long,
+        env,
+        requires = "somfy_client_id",
+        requires = "somfy_username",
+        requires = "somfy_password_input"
+    )]
+    somfy_client_secret_file: Option<PathBuf>,
+
     /// Somfy Protect API account username
     #[clap(
         long,
         env,
         requires = "somfy_client_id",
-        requires = "somfy_client_secret",
-        requires = "somfy_password"
+        requires = "somfy_client_secret_input",
+        requires = "somfy_password_input"
     )]
     somfy_username: Option<String>,
 
@@ -108,20 +230,519 @@ struct Cli {
         long,
         env,
         requires = "somfy_client_id",
-        requires = "somfy_client_secret",
+        requires = "somfy_client_secret_input",
         requires = "somfy_username"
     )]
     somfy_password: Option<String>,
+
+    /// File containing the Somfy Protect API account password
+    #[clap(
+        long,
+        env,
+        requires = "somfy_client_id",
+        requires = "somfy_client_secret_input",
+        requires = "somfy_username"
+    )]
+    somfy_password_file: Option<PathBuf>,
+
+    /// Key encrypting the Somfy Protect API OAuth refresh token saved in the state file, the
+    /// token isn't saved without key
+    #[clap(long, env, requires = "somfy_client_id")]
+    somfy_token_key: Option<String>,
+
+    /// File containing the key encrypting the Somfy Protect API OAuth refresh token
+    #[clap(long, env, requires = "somfy_client_id")]
+    somfy_token_key_file: Option<PathBuf>,
 }
 
-impl From<&Cli> for StoveDiscoveryActorConfiguration {
+impl SomfyArgs {
+    /// Settings of the API client, the account actors are restarted when they change.
+    fn client_settings(&self) -> (&Option<Url>, &Option<Url>, [&Option<String>; 5]) {
+        (
+            &self.somfy_api_baseurl,
+            &self.somfy_auth_baseurl,
+            [
+                &self.somfy_client_id,
+                &self.somfy_client_secret,
+                &self.somfy_username,
+                &self.somfy_password,
+                &self.somfy_token_key,
+            ],
+        )
+    }
+
+    fn read_secret_files(&mut self) -> Result<()> {
+        cli::read_secret_file(
+            &mut self.somfy_client_secret,
+            &self.somfy_client_secret_file,
+        )?;
+        cli::read_secret_file(&mut self.somfy_password, &self.somfy_password_file)?;
+        cli::read_secret_file(&mut self.somfy_token_key, &self.somfy_token_key_file)
+    }
+
+    fn somfy_configuration(&self, account: Option<String>, cli: &Cli) -> SomfyActorConfiguration {
+        SomfyActorConfiguration {
+            account,
+            missing_device_removal_threshold: cli.missing_device_removal_threshold,
+            topics: cli.into(),
+        }
+    }
+}
+
+/// Rika Firenet account settings, shared by the command line and the named accounts of the
+/// configuration file.
+#[derive(Args, Clone)]
+#[clap(group(ArgGroup::new("rika_password_input").args(["rika_password", "rika_password_file"])))]
+struct RikaArgs {
+    /// Rika API base URL
+    #[clap(long, env)]
+    rika_baseurl: Option<Url>,
+
+    /// Rika username
+    #[clap(long, env, requires = "rika_password_input")]
+    rika_username: Option<String>,
+
+    /// Rika password
+    #[clap(long, env, requires = "rika_username")]
+    rika_password: Option<String>,
+
+    /// File containing the Rika password
+    #[clap(long, env, requires = "rika_username")]
+    rika_password_file: Option<PathBuf>,
+
+    /// Rika stove discovery scan interval
+    #[clap(long, env, value_parser = cli::parse_time_delta_range, default_value = "6d..8d")]
+    rika_stove_discovery_repeat_interval: RangeInclusive<Duration>,
+
+    /// Rika stove discovery exponential backoff ceil
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "8h")]
+    rika_stove_discovery_backoff_ceil: Duration,
+
+    /// Rika stove status update interval, optionally depending on months and hours, e.g.
+    /// "Oct-Apr 18:00-23:00 1m; 30m"
+    #[clap(long, env, value_parser = cli::parse_repeat_schedule, default_value = "8m..12m")]
+    rika_stove_status_repeat_interval: Schedule,
+
+    /// Rika stove status update exponential backoff ceil
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "8h")]
+    rika_stove_status_backoff_ceil: Duration,
+
+    /// Rika stove status update interval following a command or an ignition, startup or burnout
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "30s")]
+    rika_stove_status_fast_repeat_interval: Duration,
+
+    /// Duration of the fast Rika stove status updates, 0s to disable them
+    #[clap(long, env, value_parser = cli::parse_time_delta, default_value = "10m")]
+    rika_stove_status_fast_repeat_window: Duration,
+
+    /// Rika API requests budget of the account, shared by the stoves discovery, the status
+    /// updates and the commands, 0 for no limit
+    #[clap(long, env, default_value_t = 120)]
+    rika_requests_per_hour: u32,
+}
+
+impl RikaArgs {
+    /// Settings of the API client, the account actors are restarted when they change.
+    fn client_settings(&self) -> (&Option<Url>, &Option<String>, &Option<String>) {
+        (&self.rika_baseurl, &self.rika_username, &self.rika_password)
+    }
+
+    fn stove_discovery_configuration(
+        &self,
+        account: Option<String>,
+        cli: &Cli,
+    ) -> StoveDiscoveryActorConfiguration {
+        StoveDiscoveryActorConfiguration {
+            account,
+            stove_discovery_repeat_interval: self.rika_stove_discovery_repeat_interval.clone(),
+            stove_discovery_backoff_ceil: self.rika_stove_discovery_backoff_ceil,
+            stove_status_repeat_schedule: self.rika_stove_status_repeat_interval.clone(),
+            backoff_jitter: cli.backoff_jitter,
+            timezone: cli.timezone,
+            stove_status_backoff_ceil: self.rika_stove_status_backoff_ceil,
+            stove_status_fast_repeat_interval: self.rika_stove_status_fast_repeat_interval,
+            stove_status_fast_repeat_window: self.rika_stove_status_fast_repeat_window,
+            missing_device_removal_threshold: cli.missing_device_removal_threshold,
+            requests_per_hour: self.rika_requests_per_hour,
+            topics: cli.into(),
+        }
+    }
+}
+
+impl Cli {
+    /// Parse the command line, then read the secrets given as files.
+    fn load() -> Result<Self> {
+        Self::from_configuration(config::parse::<Cli>()?)
+    }
+
+    /// Same as [Cli::load], but invalid arguments are reported as errors instead of exiting.
+    fn reload() -> Result<Self> {
+        Self::from_configuration(config::reparse::<Cli>()?)
+    }
+
+    fn from_configuration(configuration: Configuration<Cli>) -> Result<Self> {
+        let rika_accounts: Vec<(String, RikaArgs)> = configuration.accounts("rika")?;
+        let somfy_accounts: Vec<(String, SomfyArgs)> = configuration.accounts("somfy")?;
+        let mut cli = Cli {
+            rika_accounts,
+            somfy_accounts,
+            ..configuration.cli
+        };
+        cli::read_secret_file(&mut cli.mqtt_password, &cli.mqtt_password_file)?;
+        cli::read_secret_file(&mut cli.rika.rika_password, &cli.rika.rika_password_file)?;
+        for (name, rika) in cli.rika_accounts.iter_mut() {
+            cli::read_secret_file(&mut rika.rika_password, &rika.rika_password_file)?;
+            if rika.rika_username.is_none() || rika.rika_password.is_none() {
+                bail!("Rika account {name} requires a username and a password");
+            }
+        }
+        cli.somfy.read_secret_files()?;
+        for (name, somfy) in cli.somfy_accounts.iter_mut() {
+            somfy.read_secret_files()?;
+            if somfy.somfy_client_id.is_none()
+                || somfy.somfy_client_secret.is_none()
+                || somfy.somfy_username.is_none()
+                || somfy.somfy_password.is_none()
+            {
+                bail!("Somfy Protect account {name} requires a client id, a client secret, a username and a password");
+            }
+        }
+        Ok(cli)
+    }
+
+    /// Settings only applied at startup which differ from the ones of `other`.
+    fn changed_startup_settings(&self, other: &Cli) -> Vec<&'static str> {
+        self.startup_settings(other)
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(setting, _)| setting)
+            .collect()
+    }
+
+    /// Settings only applied at startup, along with whether they differ from the ones of `other`.
+    fn startup_settings(&self, other: &Cli) -> [(&'static str, bool); 17] {
+        [
+            (
+                "mqtt_broker_url",
+                self.mqtt_broker_url != other.mqtt_broker_url,
+            ),
+            ("mqtt_username", self.mqtt_username != other.mqtt_username),
+            ("mqtt_password", self.mqtt_password != other.mqtt_password),
+            ("mqtt_ca_file", self.mqtt_ca_file != other.mqtt_ca_file),
+            (
+                "mqtt_client_cert_file",
+                self.mqtt_client_cert_file != other.mqtt_client_cert_file,
+            ),
+            (
+                "mqtt_client_key_file",
+                self.mqtt_client_key_file != other.mqtt_client_key_file,
+            ),
+            (
+                "mqtt_tls_server_name",
+                self.mqtt_tls_server_name != other.mqtt_tls_server_name,
+            ),
+            (
+                "ha_discovery_prefix",
+                self.ha_discovery_prefix != other.ha_discovery_prefix,
+            ),
+            (
+                "ha_status_topic",
+                self.ha_status_topic != other.ha_status_topic,
+            ),
+            (
+                "mqtt_topic_prefix",
+                self.mqtt_topic_prefix != other.mqtt_topic_prefix,
+            ),
+            ("http_bind", self.http_bind != other.http_bind),
+            ("http_disabled", self.http_disabled != other.http_disabled),
+            (
+                "readiness_max_age",
+                self.readiness_max_age != other.readiness_max_age,
+            ),
+            (
+                "circuit_breaker_failure_threshold",
+                self.circuit_breaker_failure_threshold != other.circuit_breaker_failure_threshold,
+            ),
+            (
+                "circuit_breaker_open_duration",
+                self.circuit_breaker_open_duration != other.circuit_breaker_open_duration,
+            ),
+            ("state_file", self.state_file != other.state_file),
+            (
+                "min_restart_interval",
+                self.min_restart_interval != other.min_restart_interval,
+            ),
+        ]
+    }
+}
+
+impl From<&Cli> for Topics {
+    fn from(value: &Cli) -> Self {
+        Self {
+            discovery_prefix: value.ha_discovery_prefix.clone(),
+            ha_status_topic: value.ha_status_topic.clone(),
+            namespace: value.mqtt_topic_prefix.clone(),
+        }
+    }
+}
+
+impl From<&Cli> for CircuitBreakerConfiguration {
+    fn from(value: &Cli) -> Self {
+        Self {
+            failure_threshold: value.circuit_breaker_failure_threshold,
+            open_duration: value.circuit_breaker_open_duration,
+            topics: value.into(),
+        }
+    }
+}
+
+impl From<&Cli> for MqttActorConfiguration {
     fn from(value: &Cli) -> Self {
         Self {
-            stove_discovery_repeat_interval: value.rika_stove_discovery_repeat_interval.clone(),
-            stove_discovery_backoff_ceil: value.rika_stove_discovery_backoff_ceil,
-            stove_status_repeat_interval: value.rika_stove_status_repeat_interval.clone(),
-            stove_status_backoff_ceil: value.rika_stove_status_backoff_ceil,
+            broker_url: value.mqtt_broker_url.clone(),
+            username: value.mqtt_username.clone(),
+            password: value.mqtt_password.clone().unwrap_or_default(),
+            tls: TlsOptions {
+                ca_file: value.mqtt_ca_file.clone(),
+                client_cert_file: value.mqtt_client_cert_file.clone(),
+                client_key_file: value.mqtt_client_key_file.clone(),
+                server_name: value.mqtt_tls_server_name.clone(),
+            },
+            topics: value.into(),
+        }
+    }
+}
+
+/// Provider actors running for every configured account.
+struct Bridge {
+    cli: Cli,
+    mqtt_addr: Addr<MqttActor>,
+    health: HealthRegistry,
+    state: StateStore,
+    rika: BTreeMap<Option<String>, (RikaArgs, Addr<StoveDiscoveryActor>)>,
+    somfy: BTreeMap<Option<String>, (SomfyArgs, Addr<SomfyActor>)>,
+    /// Shared by the accounts of a provider, created along with the first one
+    rika_circuit_breaker: Option<CircuitBreaker>,
+    somfy_circuit_breaker: Option<CircuitBreaker>,
+}
+
+impl Bridge {
+    async fn start(
+        cli: Cli,
+        mqtt_addr: Addr<MqttActor>,
+        health: HealthRegistry,
+        state: StateStore,
+    ) -> Self {
+        let mut bridge = Bridge {
+            cli,
+            mqtt_addr,
+            health,
+            state,
+            rika: BTreeMap::new(),
+            somfy: BTreeMap::new(),
+            rika_circuit_breaker: None,
+            somfy_circuit_breaker: None,
+        };
+        bridge.update_providers().await;
+        bridge
+    }
+
+    /// Reads the configuration again and applies it to the providers, settings used by the MQTT
+    /// connection or the HTTP server require a restart.
+    async fn reload(&mut self) -> Result<()> {
+        let cli = Cli::reload()?;
+        let changed_settings = self.cli.changed_startup_settings(&cli);
+        if !changed_settings.is_empty() {
+            bail!(
+                "A restart is required to apply changes of {}",
+                changed_settings.join(", ")
+            );
+        }
+        self.cli = cli;
+        self.update_providers().await;
+        Ok(())
+    }
+
+    /// Starts the actors of new accounts, restarts the ones whose credentials changed, stops the
+    /// ones of removed accounts and reconfigures the others in place.
+    async fn update_providers(&mut self) {
+        let rika_accounts: BTreeMap<Option<String>, RikaArgs> = iter::once((None, &self.cli.rika))
+            .chain(
+                self.cli
+                    .rika_accounts
+                    .iter()
+                    .map(|(name, rika)| (Some(name.clone()), rika)),
+            )
+            .filter(|(_, rika)| rika.rika_username.is_some() && rika.rika_password.is_some())
+            .map(|(account, rika)| (account, rika.clone()))
+            .collect();
+        if rika_accounts.is_empty() {
+            debug!("No configuration for Rika Firenet");
+        }
+        let stale_rika_accounts: Vec<Option<String>> = self
+            .rika
+            .iter()
+            .filter(|(account, (running, _))| {
+                rika_accounts
+                    .get(*account)
+                    .is_none_or(|rika| rika.client_settings() != running.client_settings())
+            })
+            .map(|(account, _)| account.clone())
+            .collect();
+        for account in stale_rika_accounts {
+            if let Some((_, addr)) = self.rika.remove(&account) {
+                info!("Stopping Rika Firenet {}", account_name(&account));
+                let _ = addr.send(Stop).await;
+            }
+        }
+        for (account, rika) in rika_accounts {
+            let config = rika.stove_discovery_configuration(account.clone(), &self.cli);
+            let addr = match self.rika.remove(&account) {
+                Some((_, addr)) => {
+                    addr.do_send(rika::Reconfigure(config));
+                    addr
+                }
+                None => {
+                    info!("Starting Rika Firenet {}", account_name(&account));
+                    let (Some(username), Some(password)) =
+                        (&rika.rika_username, &rika.rika_password)
+                    else {
+                        continue;
+                    };
+                    let mut client_builder =
+                        RikaFirenetClientBuilder::default().credentials(username, password);
+                    if let Some(base_url) = &rika.rika_baseurl {
+                        client_builder =
+                            client_builder.base_url(base_url.strip_repeated_suffix("/"));
+                    }
+                    let circuit_breaker = self
+                        .rika_circuit_breaker
+                        .get_or_insert_with(|| {
+                            CircuitBreaker::new("Rika Firenet", &self.cli, self.mqtt_addr.clone())
+                        })
+                        .clone();
+                    StoveDiscoveryActor::new(
+                        config,
+                        self.mqtt_addr.clone(),
+                        client_builder.build(),
+                        self.health.clone(),
+                        circuit_breaker,
+                        self.state.clone(),
+                    )
+                    .start()
+                }
+            };
+            self.rika.insert(account, (rika, addr));
+        }
+
+        let somfy_accounts: BTreeMap<Option<String>, SomfyArgs> =
+            iter::once((None, &self.cli.somfy))
+                .chain(
+                    self.cli
+                        .somfy_accounts
+                        .iter()
+                        .map(|(name, somfy)| (Some(name.clone()), somfy)),
+                )
+                .filter(|(_, somfy)| {
+                    somfy.somfy_client_id.is_some()
+                        && somfy.somfy_client_secret.is_some()
+                        && somfy.somfy_username.is_some()
+                        && somfy.somfy_password.is_some()
+                })
+                .map(|(account, somfy)| (account, somfy.clone()))
+                .collect();
+        if somfy_accounts.is_empty() {
+            debug!("No configuration for Somfy Protect");
+        }
+        let stale_somfy_accounts: Vec<Option<String>> = self
+            .somfy
+            .iter()
+            .filter(|(account, (running, _))| {
+                somfy_accounts
+                    .get(*account)
+                    .is_none_or(|somfy| somfy.client_settings() != running.client_settings())
+            })
+            .map(|(account, _)| account.clone())
+            .collect();
+        for account in stale_somfy_accounts {
+            if let Some((_, addr)) = self.somfy.remove(&account) {
+                info!("Stopping Somfy Protect {}", account_name(&account));
+                let _ = addr.send(Stop).await;
+            }
         }
+        for (account, somfy) in somfy_accounts {
+            let config = somfy.somfy_configuration(account.clone(), &self.cli);
+            let addr = match self.somfy.remove(&account) {
+                Some((_, addr)) => {
+                    addr.do_send(somfy_protect::Reconfigure(config));
+                    addr
+                }
+                None => {
+                    info!("Starting Somfy Protect {}", account_name(&account));
+                    let (Some(client_id), Some(client_secret), Some(username), Some(password)) = (
+                        &somfy.somfy_client_id,
+                        &somfy.somfy_client_secret,
+                        &somfy.somfy_username,
+                        &somfy.somfy_password,
+                    ) else {
+                        continue;
+                    };
+                    let mut client_builder = SomfyApiClientBuilder::default()
+                        .with_client_credentials(client_id.clone(), client_secret.clone())
+                        .with_user_credentials(username.clone(), password.clone());
+                    if let Some(token_key) = &somfy.somfy_token_key {
+                        client_builder = client_builder.with_token_cache(
+                            self.state.task(config.task_name("token")),
+                            token_key,
+                        );
+                    }
+                    if let Some(api_base_url) = &somfy.somfy_api_baseurl {
+                        client_builder = client_builder
+                            .with_api_base_url(api_base_url.strip_repeated_suffix("/"));
+                    }
+                    if let Some(auth_base_url) = &somfy.somfy_auth_baseurl {
+                        client_builder = client_builder
+                            .with_auth_base_url(auth_base_url.strip_repeated_suffix("/"));
+                    }
+                    let circuit_breaker = self
+                        .somfy_circuit_breaker
+                        .get_or_insert_with(|| {
+                            CircuitBreaker::new("Somfy Protect", &self.cli, self.mqtt_addr.clone())
+                        })
+                        .clone();
+                    SomfyActor::new(
+                        config,
+                        self.mqtt_addr.clone(),
+                        client_builder.build(),
+                        self.health.clone(),
+                        circuit_breaker,
+                        self.state.clone(),
+                    )
+                    .start()
+                }
+            };
+            self.somfy.insert(account, (somfy, addr));
+        }
+
+        self.health
+            .set_providers_configured(!self.rika.is_empty() || !self.somfy.is_empty());
+    }
+
+    /// Flushes pending stove commands, then marks every device offline and saves the state.
+    async fn shutdown(&self) {
+        join_all(self.rika.values().map(|(_, addr)| addr.send(Shutdown))).await;
+        if let Err(error) = self.mqtt_addr.send(Shutdown).await {
+            warn!("Unable to disconnect from MQTT broker: {error}");
+        }
+        let state = self.state.clone();
+        let _ = actix_web::rt::task::spawn_blocking(move || state.flush()).await;
+    }
+}
+
+fn account_name(account: &Option<String>) -> String {
+    match account {
+        Some(account) => format!("account {account}"),
+        None => "default account".to_string(),
     }
 }
 
@@ -129,54 +750,212 @@ impl From<&Cli> for StoveDiscoveryActorConfiguration {
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let cli: Cli = Parser::parse();
+    let cli = Cli::load()?;
+    let health = HealthRegistry::new(cli.readiness_max_age);
+    let state = StateStore::load(cli.state_file.clone(), cli.min_restart_interval);
 
-    let mqtt = MqttActor::new(&cli.mqtt_broker_url, &cli.mqtt_username, &cli.mqtt_password);
+    let mqtt = MqttActor::new(&cli, health.clone())?;
     let mqtt_addr = mqtt.start();
 
-    match (&cli.rika_username, &cli.rika_password) {
-        (Some(username), Some(password)) => {
-            let mut client_builder =
-                RikaFirenetClientBuilder::default().credentials(username, password);
-            if let Some(base_url) = &cli.rika_baseurl {
-                client_builder = client_builder.base_url(base_url.strip_repeated_suffix("/"));
-            }
-            let rika = StoveDiscoveryActor::new(&cli, mqtt_addr.clone(), client_builder.build());
-            rika.start();
-        }
-        (_, _) => debug!("No configuration for Rika Firenet"),
-    }
-
-    match (
-        cli.somfy_client_id,
-        cli.somfy_client_secret,
-        cli.somfy_username,
-        cli.somfy_password,
-    ) {
-        (Some(client_id), Some(client_secret), Some(username), Some(password)) => {
-            let mut client_builder = SomfyProtectClientBuilder::default()
-                .with_client_credentials(client_id, client_secret)
-                .with_user_credentials(username, password);
-            if let Some(api_base_url) = cli.somfy_api_baseurl {
-                client_builder =
-                    client_builder.with_api_base_url(api_base_url.strip_repeated_suffix("/"));
+    info!("{} version {}", app_infos::name(), app_infos::version());
+    if let Some(config) = &cli.config {
+        info!("Using configuration file {}", config.display());
+    }
+
+    let (reload_trigger, mut reload_requests) = ReloadTrigger::new();
+    reload_trigger.on_hangup_signal()?;
+
+    let server_handle = if cli.http_disabled {
+        info!("HTTP server disabled");
+        None
+    } else {
+        let server_health = health.clone();
+        let server_reload_trigger = reload_trigger.clone();
+        // admin endpoints are unauthenticated, they are only served to local clients
+        let admin_enabled = cli.http_bind.is_local();
+        if !admin_enabled {
+            warn!(
+                "Admin endpoints disabled, {} is neither a loopback address nor a unix socket",
+                cli.http_bind
+            );
+        }
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(Logger::default())
+                .app_data(web::Data::new(server_health.clone()))
+                .app_data(web::Data::new(server_reload_trigger.clone()))
+                .configure(health::configure)
+                .configure(metrics::configure)
+                .configure(|cfg| {
+                    if admin_enabled {
+                        reload::configure(cfg)
+                    }
+                })
+        })
+        .disable_signals();
+        let server = match &cli.http_bind {
+            HttpBind::Tcp(address) => server.bind(address),
+            #[cfg(unix)]
+            HttpBind::Unix(path) => remove_stale_socket(path).and_then(|_| server.bind_uds(path)),
+            #[cfg(not(unix))]
+            HttpBind::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+        .with_context(|| format!("Unable to bind HTTP server to {}", cli.http_bind))?
+        .run();
+        info!("HTTP server listening on {}", cli.http_bind);
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        Some(handle)
+    };
+
+    let mut bridge = Bridge::start(cli, mqtt_addr, health, state).await;
+
+    let mut termination = pin!(shutdown::termination_signal());
+    loop {
+        match select(termination.as_mut(), reload_requests.next()).await {
+            Either::Left((result, _)) => {
+                result?;
+                break;
             }
-            if let Some(auth_base_url) = cli.somfy_auth_baseurl {
-                client_builder =
-                    client_builder.with_auth_base_url(auth_base_url.strip_repeated_suffix("/"));
+            Either::Right((Some(ReloadRequest(outcome)), _)) => {
+                info!("Reloading configuration");
+                let result = bridge.reload().await;
+                match &result {
+                    Ok(()) => info!("Configuration reloaded"),
+                    Err(error) => error!("Unable to reload configuration: {error:#}"),
+                }
+                let _ = outcome.send(result);
             }
-            let somfy = SomfyActor::new(mqtt_addr, client_builder.build());
-            somfy.start();
+            Either::Right((None, _)) => {}
         }
-        (_, _, _, _) => debug!("No configuration for Somfy Protect"),
     }
 
-    info!("{} version {}", app_infos::name(), app_infos::version());
-
-    HttpServer::new(move || App::new().wrap(Logger::default()))
-        .bind("127.0.0.1:8080")?
-        .run()
-        .await?;
+    info!("Shutting down");
+    let shutdown_timeout = bridge.cli.shutdown_timeout;
+    let shutdown = async {
+        bridge.shutdown().await;
+        if let Some(handle) = server_handle {
+            handle.stop(true).await;
+        }
+    };
+    if actix_web::rt::time::timeout(shutdown_timeout, shutdown)
+        .await
+        .is_err()
+    {
+        warn!("Graceful shutdown not completed within {shutdown_timeout:?}, stopping anyway");
+    }
+    actix::System::current().stop();
 
     Ok(())
 }
+
+/// Removes the unix socket file left over by a previous run, unless a server still listens on it.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::fs;
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+            _ => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+
+    use super::Cli;
+
+    /// Settings applied by a reload. Secret files are compared through the secret they contain.
+    const RELOADED_SETTINGS: [&str; 27] = [
+        "config",
+        "mqtt_password_file",
+        "missing_device_removal_threshold",
+        "backoff_jitter",
+        "timezone",
+        "shutdown_timeout",
+        "rika_baseurl",
+        "rika_username",
+        "rika_password",
+        "rika_password_file",
+        "rika_stove_discovery_repeat_interval",
+        "rika_stove_discovery_backoff_ceil",
+        "rika_stove_status_repeat_interval",
+        "rika_stove_status_backoff_ceil",
+        "rika_stove_status_fast_repeat_interval",
+        "rika_stove_status_fast_repeat_window",
+        "rika_requests_per_hour",
+        "somfy_api_baseurl",
+        "somfy_auth_baseurl",
+        "somfy_client_id",
+        "somfy_client_secret",
+        "somfy_client_secret_file",
+        "somfy_username",
+        "somfy_password",
+        "somfy_password_file",
+        "somfy_token_key",
+        "somfy_token_key_file",
+    ];
+
+    #[test]
+    fn every_setting_is_either_reloaded_or_applied_at_startup() {
+        let cli = Cli::try_parse_from([
+            "hass-mqtt-bridge",
+            "--mqtt-username",
+            "bridge",
+            "--mqtt-password",
+            "s3cr3t",
+        ])
+        .unwrap();
+        let command = Cli::command();
+        let startup_settings = cli.startup_settings(&cli).map(|(setting, _)| setting);
+        for setting in startup_settings {
+            assert!(
+                command.get_arguments().any(|arg| arg.get_id() == setting),
+                "{setting} is not an argument"
+            );
+            assert!(
+                !RELOADED_SETTINGS.contains(&setting),
+                "{setting} is both reloaded and applied at startup"
+            );
+        }
+        for arg in command.get_arguments() {
+            let setting = arg.get_id().as_str();
+            assert!(
+                startup_settings.contains(&setting) || RELOADED_SETTINGS.contains(&setting),
+                "{setting} must be classified as reloaded or applied at startup"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn can_remove_stale_unix_sockets() {
+        use std::{fs, os::unix::net::UnixListener};
+
+        use super::remove_stale_socket;
+
+        let path = std::env::temp_dir().join(format!(
+            "hass-mqtt-bridge-socket-test-{}.sock",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        remove_stale_socket(&path).unwrap();
+        assert!(path.exists(), "socket of a running server is kept");
+
+        drop(listener);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists(), "stale socket is removed");
+
+        let _ = fs::remove_file(&path);
+    }
+}
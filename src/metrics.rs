@@ -0,0 +1,106 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    Encoder, GaugeVec, HistogramVec, IntCounter, IntCounterVec, TextEncoder,
+};
+use std::{future::Future, time::Duration};
+
+lazy_static! {
+    static ref CLOUD_API_CALLS: IntCounterVec = register_int_counter_vec!(
+        "hass_mqtt_bridge_cloud_api_calls_total",
+        "Cloud API calls by provider, operation and outcome",
+        &["provider", "operation", "outcome"]
+    )
+    .expect("A valid cloud API calls metric");
+    static ref CLOUD_API_CALL_DURATION: HistogramVec = register_histogram_vec!(
+        "hass_mqtt_bridge_cloud_api_call_duration_seconds",
+        "Cloud API calls latency by provider and operation",
+        &["provider", "operation"]
+    )
+    .expect("A valid cloud API calls latency metric");
+    static ref MQTT_PUBLISHES: IntCounterVec = register_int_counter_vec!(
+        "hass_mqtt_bridge_mqtt_publishes_total",
+        "MQTT publications by outcome",
+        &["outcome"]
+    )
+    .expect("A valid MQTT publications metric");
+    static ref MQTT_RECONNECTS: IntCounter = register_int_counter!(
+        "hass_mqtt_bridge_mqtt_reconnects_total",
+        "MQTT connection errors followed by a reconnection attempt"
+    )
+    .expect("A valid MQTT reconnections metric");
+    static ref STOVE_COMMANDS: IntCounterVec = register_int_counter_vec!(
+        "hass_mqtt_bridge_rika_stove_commands_total",
+        "Rika stove commands received from MQTT by command",
+        &["command"]
+    )
+    .expect("A valid stove commands metric");
+    static ref TASK_BACKOFF_DELAY: GaugeVec = register_gauge_vec!(
+        "hass_mqtt_bridge_task_backoff_delay_seconds",
+        "Current backoff delay of a repeated task, 0 when its last execution succeeded",
+        &["task"]
+    )
+    .expect("A valid task backoff delay metric");
+}
+
+fn outcome<T, E>(result: &Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
+
+/// Execute a cloud API call while recording its latency and outcome.
+pub async fn observe_api_call<T, E, Fut>(provider: &str, operation: &str, call: Fut) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    let timer = CLOUD_API_CALL_DURATION
+        .with_label_values(&[provider, operation])
+        .start_timer();
+    let result = call.await;
+    timer.observe_duration();
+    CLOUD_API_CALLS
+        .with_label_values(&[provider, operation, outcome(&result)])
+        .inc();
+    result
+}
+
+pub fn record_mqtt_publish<T, E>(result: &Result<T, E>) {
+    MQTT_PUBLISHES.with_label_values(&[outcome(result)]).inc();
+}
+
+pub fn record_mqtt_reconnect() {
+    MQTT_RECONNECTS.inc();
+}
+
+pub fn record_stove_command(command: &str) {
+    STOVE_COMMANDS.with_label_values(&[command]).inc();
+}
+
+pub fn set_task_backoff_delay(task: &str, delay: Duration) {
+    TASK_BACKOFF_DELAY
+        .with_label_values(&[task])
+        .set(delay.as_secs_f64());
+}
+
+pub fn remove_task(task: &str) {
+    let _ = TASK_BACKOFF_DELAY.remove_label_values(&[task]);
+}
+
+#[get("/metrics")]
+async fn export() -> impl Responder {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(export);
+}
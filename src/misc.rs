@@ -9,7 +9,7 @@ use unicode_normalization::UnicodeNormalization;
 use url::Url;
 
 pub(crate) mod app_infos {
-    use ha_mqtt_discovery::mqtt::common::Origin;
+    use ha_mqtt_discovery::mqtt::common::{Device, Origin};
     use package_info::PackageInfo;
     use package_info_derive::PackageInfo;
 
@@ -31,6 +31,14 @@ pub(crate) mod app_infos {
             support_url: CargoPackageInfo::homepage().or(CargoPackageInfo::repository()),
         }
     }
+
+    /// The bridge itself, holding the entities describing its own state.
+    pub(crate) fn device() -> Device {
+        Device::default()
+            .name(format!("{} ({})", name(), super::hostname()))
+            .add_identifier(format!("{}-{}", name(), super::hostname()))
+            .sw_version(version())
+    }
 }
 
 pub fn hostname() -> String {
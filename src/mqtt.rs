@@ -1,70 +1,237 @@
 use actix::prelude::*;
 use actix_web::rt::time;
+use anyhow::{bail, Context as _, Result};
 use async_stream::stream;
+use futures::channel::oneshot;
+use ha_mqtt_discovery::mqtt::common::AvailabilityCheck;
 use ha_mqtt_discovery::v5::{
     mqttbytes::{
-        v5::{ConnAck, Packet, Publish},
+        v5::{ConnAck, LastWill, Packet, Publish},
         QoS,
     },
     AsyncClient, ClientError, Event, MqttOptions,
 };
 use ha_mqtt_discovery::{Entity, HomeAssistantMqtt};
-use log::{error, info, trace};
+use log::{debug, error, info, trace};
+use rand::Rng;
+use rumqttc::{Outgoing, TlsConfiguration, Transport};
 use serde::Serialize;
 use serde_json::Value;
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    time::Duration,
+};
 use url::Url;
 
-use crate::misc::{app_infos, hostname, HumanReadable};
+use crate::health::HealthRegistry;
+use crate::metrics;
+use crate::misc::{app_infos, hostname, HumanReadable, SuffixStrip};
+use crate::repeat::policy::{ExponentialBackoff, RepeatPolicy};
+use crate::shutdown::Shutdown;
+use crate::tls::TlsOptions;
 
-const BIRTH_LAST_WILL_TOPIC: &str = "homeassistant/status";
 const BIRTH_PAYLOAD: &str = "online";
 const LAST_WILL_PAYLOAD: &str = "offline";
+/// Home Assistant recommends to wait a random delay before re-publishing discovery configurations
+/// to avoid all clients flooding the broker at the same time.
+const REPUBLISH_DELAY: RangeInclusive<Duration> = Duration::from_secs(1)..=Duration::from_secs(5);
+/// Exposes the flag set by [PublishEntityData::stale] as an entity attribute.
+pub const STALE_ATTRIBUTES_TEMPLATE: &str =
+    "{{ {'stale': value_json.stale | default(false)} | tojson }}";
+
+/// Layout of the MQTT topics shared by the bridge and the providers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Topics {
+    /// Home Assistant discovery prefix.
+    pub discovery_prefix: String,
+    /// Topic where Home Assistant publishes its birth and last will messages.
+    pub ha_status_topic: String,
+    /// Namespace prepended to every topic owned by the bridge.
+    pub namespace: Option<String>,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Self {
+            discovery_prefix: "homeassistant".to_string(),
+            ha_status_topic: "homeassistant/status".to_string(),
+            namespace: None,
+        }
+    }
+}
+
+impl Topics {
+    pub fn namespaced(&self, topic: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}/{topic}", namespace.strip_repeated_suffix("/")),
+            None => topic.to_string(),
+        }
+    }
+
+    /// Topic owned by this bridge instance.
+    pub fn bridge(&self, topic: &str) -> String {
+        self.namespaced(&format!("{}/{}/{topic}", app_infos::name(), hostname()))
+    }
+
+    /// Topic where the bridge announces its own availability, the MQTT last will marks it offline.
+    pub fn bridge_status(&self) -> String {
+        self.bridge("status")
+    }
+
+    /// An availability check every entity should include to become unavailable as soon as the
+    /// bridge is disconnected from the broker.
+    pub fn bridge_availability(&self) -> AvailabilityCheck {
+        AvailabilityCheck::topic(self.bridge_status())
+            .payload_available(BIRTH_PAYLOAD)
+            .payload_not_available(LAST_WILL_PAYLOAD)
+    }
+
+    fn discovery_topic_prefix(&self) -> String {
+        format!("{}/", self.discovery_prefix.strip_repeated_suffix("/"))
+    }
+}
 
 pub struct MqttActor {
+    topics: Topics,
+    health: HealthRegistry,
     mqtt_options: MqttOptions,
     mqtt_client: Option<AsyncClient>,
     ha_mqtt: Option<HomeAssistantMqtt>,
     listeners: HashSet<Recipient<MqttMessage>>,
+    published_entities: HashMap<String, Entity>,
+    published_data: HashMap<String, Value>,
+    /// Notified once the disconnection is sent to the broker during the shutdown
+    disconnected: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Clone)]
+pub struct MqttActorConfiguration {
+    pub broker_url: Url,
+    pub username: String,
+    pub password: String,
+    pub tls: TlsOptions,
+    pub topics: Topics,
+}
+
+impl MqttActorConfiguration {
+    fn tls_configuration(&self) -> Result<TlsConfiguration> {
+        self.tls.build().context("Invalid MQTT TLS configuration")
+    }
+
+    /// Websocket transports expect the whole URL, including the path, as the broker address.
+    fn websocket_url(&self) -> String {
+        let mut url = self.broker_url.clone();
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        url.to_string()
+    }
 }
 
 impl MqttActor {
-    pub fn new(broker_url: &Url, username: &String, password: &String) -> Self {
-        let mqtt_options = MqttOptions::new(
+    pub fn new<C: Into<MqttActorConfiguration>>(
+        configuration: C,
+        health: HealthRegistry,
+    ) -> Result<Self> {
+        let config: MqttActorConfiguration = configuration.into();
+        let broker_url = &config.broker_url;
+        let host = broker_url
+            .host()
+            .context("A broker URL with a host")?
+            .to_string();
+        let (broker_addr, port, transport) = match broker_url.scheme() {
+            "mqtt" => (host, broker_url.port().unwrap_or(1883), Transport::tcp()),
+            "mqtts" => (
+                host,
+                broker_url.port().unwrap_or(8883),
+                Transport::tls_with_config(config.tls_configuration()?),
+            ),
+            "ws" => (
+                config.websocket_url(),
+                broker_url.port().unwrap_or(80),
+                Transport::ws(),
+            ),
+            "wss" => (
+                config.websocket_url(),
+                broker_url.port().unwrap_or(443),
+                Transport::wss_with_config(config.tls_configuration()?),
+            ),
+            scheme => bail!("Unsupported MQTT broker URL scheme: {scheme}"),
+        };
+        let mut mqtt_options = MqttOptions::new(
             format!("{}@{}", app_infos::name(), hostname()),
-            broker_url
-                .host()
-                .expect("A broker URL with a host")
-                .to_string(),
-            broker_url.port().expect("A broker URL with a port"),
-        )
-        .set_credentials(username, password)
-        .clone();
-        MqttActor {
+            broker_addr,
+            port,
+        );
+        mqtt_options
+            .set_transport(transport)
+            .set_credentials(&config.username, &config.password)
+            .set_last_will(LastWill::new(
+                config.topics.bridge_status(),
+                LAST_WILL_PAYLOAD,
+                QoS::AtLeastOnce,
+                true,
+                None,
+            ));
+        Ok(MqttActor {
+            topics: config.topics,
+            health,
             mqtt_options,
             mqtt_client: None,
             ha_mqtt: None,
             listeners: HashSet::new(),
-        }
+            published_entities: HashMap::new(),
+            published_data: HashMap::new(),
+            disconnected: None,
+        })
     }
 
     fn subscribe_ha_events(&self, ctx: &mut Context<Self>, ack: ConnAck) {
         if let Some(client) = self.mqtt_client.clone() {
+            let ha_status_topic = self.topics.ha_status_topic.clone();
+            async move {
+                let _ = client.subscribe(ha_status_topic, QoS::AtLeastOnce).await;
+            }
+            .into_actor(self)
+            .spawn(ctx);
+        }
+    }
+
+    fn announce_bridge_online(&self, ctx: &mut Context<Self>) {
+        if let Some(client) = self.mqtt_client.clone() {
+            let topic = self.topics.bridge_status();
             async move {
-                let _ = client
-                    .subscribe(BIRTH_LAST_WILL_TOPIC, QoS::AtLeastOnce)
+                let result = client
+                    .publish(&topic, QoS::AtLeastOnce, true, BIRTH_PAYLOAD)
                     .await;
+                metrics::record_mqtt_publish(&result);
+                if let Err(error) = result {
+                    error!("Unable to publish bridge status on {topic}: {error}")
+                }
             }
             .into_actor(self)
             .spawn(ctx);
         }
     }
 
-    fn handle_event(&self, event: Event) {
+    fn handle_event(&mut self, ctx: &mut Context<Self>, event: Event) {
         trace!("event from server: {event:?}");
         match event {
+            Event::Outgoing(Outgoing::Disconnect) => {
+                if let Some(disconnected) = self.disconnected.take() {
+                    info!("Disconnected from MQTT broker");
+                    let _ = disconnected.send(());
+                    ctx.stop();
+                }
+            }
             Event::Incoming(Packet::Publish(publish)) => {
                 let message = MqttMessage::from(publish);
+                let ha_is_online = message.topic == self.topics.ha_status_topic
+                    && message.payload == BIRTH_PAYLOAD;
+                if ha_is_online {
+                    self.schedule_republish(ctx);
+                }
+                self.listeners.retain(Recipient::connected);
                 for recipient in &self.listeners {
                     recipient.do_send(message.clone());
                 }
@@ -72,6 +239,57 @@ impl MqttActor {
             _ => {}
         }
     }
+
+    fn schedule_republish(&self, ctx: &mut Context<Self>) {
+        let delay = rand::thread_rng().gen_range(REPUBLISH_DELAY);
+        info!(
+            "Home Assistant is online, re-publishing {} entities and {} data in {delay:?}",
+            self.published_entities.len(),
+            self.published_data.len()
+        );
+        ctx.run_later(delay, Self::republish);
+    }
+
+    /// Publish again every known entity configuration, then the last data published on each topic.
+    fn republish(act: &mut MqttActor, ctx: &mut Context<Self>) {
+        let Some(ha_mqtt) = act.ha_mqtt.clone() else {
+            error!("MQTT client not available");
+            return;
+        };
+        let entities: Vec<Entity> = act.published_entities.values().cloned().collect();
+        let data: Vec<(String, Value)> = act
+            .published_data
+            .iter()
+            .map(|(topic, payload)| (topic.clone(), payload.clone()))
+            .collect();
+        async move {
+            for entity in entities {
+                let result = ha_mqtt.publish_entity(entity).await;
+                metrics::record_mqtt_publish(&result);
+                if let Err(error) = result {
+                    error!("Unable to re-publish entity: {error}")
+                }
+            }
+            for (topic, payload) in data {
+                let result = ha_mqtt.publish_data(&topic, &payload, None).await;
+                metrics::record_mqtt_publish(&result);
+                if let Err(error) = result {
+                    error!("Unable to re-publish data: {error}")
+                }
+            }
+        }
+        .into_actor(act)
+        .spawn(ctx);
+    }
+}
+
+/// Discovery topics of the given entities, computed by the library publishing them. Entities
+/// without topic, e.g. without unique id, are skipped.
+fn config_topics(ha_mqtt: &HomeAssistantMqtt, entities: &[Entity]) -> Vec<String> {
+    entities
+        .iter()
+        .filter_map(|entity| ha_mqtt.config_topic(entity).ok())
+        .collect()
 }
 
 impl Actor for MqttActor {
@@ -80,21 +298,24 @@ impl Actor for MqttActor {
     fn started(&mut self, ctx: &mut Self::Context) {
         let (async_client, mut event_loop) = AsyncClient::new(self.mqtt_options.clone(), 10);
         self.mqtt_client = Some(async_client.clone());
-        self.ha_mqtt = Some(HomeAssistantMqtt::new(async_client, "homeassistant/"));
+        let discovery_topic_prefix = self.topics.discovery_topic_prefix();
+        self.ha_mqtt = Some(HomeAssistantMqtt::new(
+            async_client,
+            &discovery_topic_prefix,
+        ));
 
+        let health = self.health.clone();
         ctx.add_stream(stream! {
-            let backoff = exponential_backoff::Backoff::new(u32::MAX, Duration::from_millis(50), Duration::from_secs(300));
-            let mut backoff_session = backoff.iter();
+            let mut backoff = ExponentialBackoff::new(Duration::from_millis(50), Duration::from_secs(300));
             loop {
                 match event_loop.poll().await {
                     Ok(event) => {
-                        backoff_session = backoff.iter();
+                        backoff.reset();
                         yield event;},
                     Err(connection_error) => {
-                        let delay = match backoff_session.next() {
-                            Some(Some(delay)) => delay,
-                            _ => Duration::from_secs(300),
-                        };
+                        health.set_mqtt_connected(false);
+                        metrics::record_mqtt_reconnect();
+                        let delay = backoff.next();
                         error!("Backing off for {}: {connection_error} (see also MQTT server logs)", delay.prettify());
                         time::sleep(delay).await;
                     }
@@ -108,9 +329,11 @@ impl StreamHandler<Event> for MqttActor {
     fn handle(&mut self, msg: Event, ctx: &mut Self::Context) {
         match msg {
             Event::Incoming(Packet::ConnAck(ack)) => {
+                self.health.set_mqtt_connected(true);
+                self.announce_bridge_online(ctx);
                 self.subscribe_ha_events(ctx, ack);
             }
-            event => self.handle_event(event),
+            event => self.handle_event(ctx, event),
         }
     }
 
@@ -119,6 +342,35 @@ impl StreamHandler<Event> for MqttActor {
     }
 }
 
+/// Marks the bridge, hence every device, offline then disconnects from the broker.
+impl Handler<Shutdown> for MqttActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(client) = self.mqtt_client.clone() else {
+            return Box::pin(async {});
+        };
+        let topic = self.topics.bridge_status();
+        let (disconnected, on_disconnected) = oneshot::channel();
+        self.disconnected = Some(disconnected);
+        Box::pin(async move {
+            let result = client
+                .publish(&topic, QoS::AtLeastOnce, true, LAST_WILL_PAYLOAD)
+                .await;
+            metrics::record_mqtt_publish(&result);
+            if let Err(error) = result {
+                error!("Unable to publish bridge status on {topic}: {error}")
+            }
+            match client.disconnect().await {
+                Ok(()) => {
+                    let _ = on_disconnected.await;
+                }
+                Err(error) => error!("Unable to disconnect from MQTT broker: {error}"),
+            }
+        })
+    }
+}
+
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 pub struct EntityConfiguration(pub Entity);
@@ -127,23 +379,74 @@ impl Handler<EntityConfiguration> for MqttActor {
     type Result = ();
 
     fn handle(&mut self, msg: EntityConfiguration, ctx: &mut Self::Context) -> Self::Result {
-        if let Some(ha_mqtt) = self.ha_mqtt.clone() {
-            async move {
-                let result = ha_mqtt.publish_entity(msg.0).await;
-                if let Err(error) = result {
-                    error!("Unable to publish entity: {error}")
-                }
+        let Some(ha_mqtt) = self.ha_mqtt.clone() else {
+            error!("MQTT client not available");
+            return;
+        };
+        // entities are identified by their discovery topic, the one used to remove them
+        match ha_mqtt.config_topic(&msg.0) {
+            Ok(topic) => {
+                self.published_entities.insert(topic, msg.0.clone());
+            }
+            Err(error) => debug!("Entity won't be re-published nor removed: {error:#}"),
+        }
+        async move {
+            let result = ha_mqtt.publish_entity(msg.0).await;
+            metrics::record_mqtt_publish(&result);
+            if let Err(error) = result {
+                error!("Unable to publish entity: {error}")
             }
-            .into_actor(self)
-            .spawn(ctx);
-        } else {
-            error!("MQTT client not available")
         }
+        .into_actor(self)
+        .spawn(ctx);
     }
 }
 
+/// Removes a device from Home Assistant by publishing empty retained discovery configurations for
+/// each of its entities, and forgets the data published under its topic prefix.
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
+pub struct RemoveDevice {
+    pub topic_prefix: String,
+    pub entities: Vec<Entity>,
+}
+
+impl Handler<RemoveDevice> for MqttActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveDevice, ctx: &mut Self::Context) -> Self::Result {
+        let data_topic_prefix = format!("{}/", msg.topic_prefix);
+        self.published_data
+            .retain(|topic, _| !topic.starts_with(&data_topic_prefix));
+        let Some(ha_mqtt) = &self.ha_mqtt else {
+            error!("MQTT client not available");
+            return;
+        };
+        let config_topics = config_topics(ha_mqtt, &msg.entities);
+        for topic in &config_topics {
+            self.published_entities.remove(topic);
+        }
+        match self.mqtt_client.clone() {
+            Some(client) => {
+                async move {
+                    for topic in config_topics {
+                        let result = client.publish(&topic, QoS::AtLeastOnce, true, "").await;
+                        metrics::record_mqtt_publish(&result);
+                        if let Err(error) = result {
+                            error!("Unable to remove entity configuration {topic}: {error}")
+                        }
+                    }
+                }
+                .into_actor(self)
+                .spawn(ctx);
+            }
+            None => error!("MQTT client not available"),
+        }
+    }
+}
+
+#[derive(Message, Clone, Debug, PartialEq)]
+#[rtype(result = "()")]
 pub struct PublishEntityData {
     topic: String,
     payload: Value,
@@ -156,17 +459,28 @@ impl PublishEntityData {
             payload: serde_json::to_value(payload).unwrap_or_default(),
         }
     }
+
+    /// Flags an object payload as stale, when it was saved by a previous run, or as fresh.
+    pub fn stale(mut self, stale: bool) -> Self {
+        if let Value::Object(payload) = &mut self.payload {
+            payload.insert("stale".to_string(), Value::Bool(stale));
+        }
+        self
+    }
 }
 
 impl Handler<PublishEntityData> for MqttActor {
     type Result = ();
 
     fn handle(&mut self, msg: PublishEntityData, ctx: &mut Self::Context) -> Self::Result {
+        self.published_data
+            .insert(msg.topic.clone(), msg.payload.clone());
         match self.ha_mqtt.clone() {
             Some(ha_mqtt) => {
                 let msg = msg.clone();
                 async move {
                     let result = ha_mqtt.publish_data(&msg.topic, &msg.payload, None).await;
+                    metrics::record_mqtt_publish(&result);
                     if let Err(error) = result {
                         error!("Unable to publish data: {error}")
                     }
@@ -257,5 +571,56 @@ impl Handler<Subscribe> for MqttActor {
 
 pub trait HaMqttEntity<T> {
     fn list_entities(self) -> Vec<Entity>;
-    fn build_payloads(&self, data: T) -> Vec<PublishEntityData>;
+    fn build_payloads(&self, data: &T) -> Vec<PublishEntityData>;
+}
+
+#[cfg(test)]
+mod tests {
+    use ha_mqtt_discovery::{
+        mqtt::{binary_sensor::BinarySensor, sensor::Sensor},
+        v5::{AsyncClient, MqttOptions},
+        Entity, HomeAssistantMqtt,
+    };
+
+    use super::config_topics;
+
+    fn ha_mqtt() -> HomeAssistantMqtt {
+        let (client, _event_loop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        HomeAssistantMqtt::new(client, "homeassistant/")
+    }
+
+    #[test]
+    fn removes_entities_from_their_published_topic() {
+        let ha_mqtt = ha_mqtt();
+        let entity = Entity::Sensor(
+            Sensor::default()
+                .name("Room temperature")
+                .unique_id("stove-12345-room-temperature")
+                .object_id("stove_room_temperature"),
+        );
+        let published_topic = ha_mqtt.config_topic(&entity).unwrap();
+
+        // entities of a removed device are built again from its last data
+        let removed_entity = Entity::Sensor(
+            Sensor::default()
+                .name("Living room temperature")
+                .unique_id("stove-12345-room-temperature")
+                .object_id("stove_room_temperature"),
+        );
+        assert_eq!(
+            config_topics(&ha_mqtt, &[removed_entity]),
+            vec![published_topic]
+        );
+    }
+
+    #[test]
+    fn skips_entities_without_topic() {
+        let ha_mqtt = ha_mqtt();
+        let entities = [
+            Entity::BinarySensor(BinarySensor::default().name("Problem")),
+            Entity::BinarySensor(BinarySensor::default().unique_id("bridge-problem")),
+        ];
+        assert_eq!(config_topics(&ha_mqtt, &entities).len(), 1);
+    }
 }
@@ -0,0 +1,74 @@
+use actix::Addr;
+use ha_mqtt_discovery::{
+    mqtt::{
+        binary_sensor::BinarySensor, common::EntityCategory,
+        device_classes::BinarySensorDeviceClass,
+    },
+    Entity,
+};
+use serde_json::json;
+
+use crate::{
+    misc::{app_infos, hostname, Sluggable},
+    mqtt::{EntityConfiguration, MqttActor, PublishEntityData, Topics},
+};
+
+/// Home Assistant diagnostic binary sensor of the bridge device, raised when an account stopped
+/// calling its provider API because of an error retrying can't fix, such as invalid credentials.
+#[derive(Clone)]
+pub struct ProblemSensor {
+    name: String,
+    topics: Topics,
+    mqtt_addr: Addr<MqttActor>,
+}
+
+impl ProblemSensor {
+    /// Publishes the sensor, initially cleared.
+    pub fn new(name: String, topics: Topics, mqtt_addr: Addr<MqttActor>) -> Self {
+        let sensor = Self {
+            name,
+            topics,
+            mqtt_addr,
+        };
+        sensor
+            .mqtt_addr
+            .do_send(EntityConfiguration(sensor.entity()));
+        sensor.publish_state(None);
+        sensor
+    }
+
+    pub fn raise(&self, error: String) {
+        self.publish_state(Some(error));
+    }
+
+    fn state_topic(&self) -> String {
+        self.topics.bridge(&format!("problem/{}", self.name.slug()))
+    }
+
+    fn entity(&self) -> Entity {
+        let unique_id =
+            format!("{}-{}-{}-problem", app_infos::name(), hostname(), self.name).slug();
+        Entity::BinarySensor(
+            BinarySensor::default()
+                .name(format!("{} problem", self.name))
+                .unique_id(&unique_id)
+                .object_id(&unique_id)
+                .state_topic(self.state_topic())
+                .value_template("{{ value_json.problem }}")
+                .payload_on("True")
+                .payload_off("False")
+                .device_class(BinarySensorDeviceClass::Problem)
+                .entity_category(EntityCategory::Diagnostic)
+                .origin(app_infos::origin())
+                .device(app_infos::device())
+                .availability(vec![self.topics.bridge_availability()]),
+        )
+    }
+
+    fn publish_state(&self, error: Option<String>) {
+        self.mqtt_addr.do_send(PublishEntityData::new(
+            self.state_topic(),
+            json!({ "problem": error.is_some(), "error": error }),
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use log::info;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::misc::HumanReadable;
+
+/// Share of the hourly budget that can be spent at once, after a quiet period.
+const BURST_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Hourly budget of API requests shared by every task of an account, requests exceeding it are
+/// delayed, which stretches the repeat intervals of the tasks.
+#[derive(Clone)]
+pub struct RateLimiter {
+    name: String,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// A budget of 0 requests per hour disables the limit.
+    pub fn new(name: String, requests_per_hour: u32) -> Self {
+        Self {
+            name,
+            bucket: Arc::new(Mutex::new(TokenBucket::new(
+                requests_per_hour,
+                Instant::now(),
+            ))),
+        }
+    }
+
+    pub fn set_requests_per_hour(&self, requests_per_hour: u32) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.requests_per_hour != requests_per_hour {
+            info!(
+                "Changing {} requests budget to {requests_per_hour} per hour",
+                self.name
+            );
+            bucket.set_requests_per_hour(requests_per_hour, Instant::now());
+        }
+    }
+
+    /// Books the next request, it must be sent once the returned delay is over.
+    pub fn reserve(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let delay = bucket.reserve(Instant::now());
+        if !delay.is_zero() {
+            info!(
+                "{} requests budget of {} per hour is exhausted, delaying next request by {}",
+                self.name,
+                bucket.requests_per_hour,
+                delay.prettify()
+            );
+        }
+        delay
+    }
+
+    /// Books a request that can't wait, such as a stove command, the following requests are
+    /// delayed instead.
+    pub fn consume(&self) {
+        self.bucket.lock().unwrap().reserve(Instant::now());
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    requests_per_hour: u32,
+    /// Available requests, negative when requests are already booked ahead of time
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(requests_per_hour: u32, now: Instant) -> Self {
+        let mut bucket = Self {
+            requests_per_hour,
+            tokens: 0.0,
+            last_refill: now,
+        };
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    fn rate_per_second(&self) -> f64 {
+        self.requests_per_hour as f64 / 3600.0
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.rate_per_second() * BURST_DURATION.as_secs_f64()).max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate_per_second()).min(self.capacity());
+        self.last_refill = now;
+    }
+
+    fn set_requests_per_hour(&mut self, requests_per_hour: u32, now: Instant) {
+        self.refill(now);
+        self.requests_per_hour = requests_per_hour;
+        self.tokens = self.tokens.min(self.capacity());
+    }
+
+    fn reserve(&mut self, now: Instant) -> Duration {
+        if self.requests_per_hour == 0 {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis((-self.tokens / self.rate_per_second() * 1000.0).ceil() as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn delays_requests_exceeding_the_burst() {
+        let now = Instant::now();
+        // 5 minutes burst of 60 requests per hour
+        let mut bucket = TokenBucket::new(60, now);
+
+        for _ in 0..5 {
+            assert_eq!(bucket.reserve(now), Duration::ZERO);
+        }
+        assert_eq!(bucket.reserve(now), Duration::from_secs(60));
+        assert_eq!(
+            bucket.reserve(now),
+            Duration::from_secs(120),
+            "requests are queued behind the ones already delayed"
+        );
+    }
+
+    #[test]
+    fn refills_at_the_hourly_rate_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(60, now);
+        for _ in 0..5 {
+            bucket.reserve(now);
+        }
+
+        let later = now + Duration::from_secs(90);
+        assert_eq!(bucket.reserve(later), Duration::ZERO);
+        assert_eq!(bucket.reserve(later), Duration::from_secs(30));
+
+        let much_later = later + Duration::from_secs(3600);
+        for _ in 0..5 {
+            assert_eq!(bucket.reserve(much_later), Duration::ZERO);
+        }
+        assert_ne!(bucket.reserve(much_later), Duration::ZERO);
+    }
+
+    #[test]
+    fn never_delays_requests_without_budget() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(0, now);
+
+        for _ in 0..1000 {
+            assert_eq!(bucket.reserve(now), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn applies_a_new_budget_to_the_available_requests() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(600, now);
+
+        bucket.set_requests_per_hour(12, now);
+        assert_eq!(bucket.reserve(now), Duration::ZERO);
+        assert_eq!(bucket.reserve(now), Duration::from_secs(300));
+    }
+}
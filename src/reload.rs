@@ -0,0 +1,56 @@
+use actix_web::{
+    post,
+    rt::signal::unix::{signal, SignalKind},
+    web, HttpResponse, Responder,
+};
+use anyhow::{anyhow, Result};
+use futures::channel::{mpsc, oneshot};
+
+/// Asks the bridge to read its configuration again, the outcome is sent once applied.
+pub struct ReloadRequest(pub oneshot::Sender<Result<()>>);
+
+/// Requests configuration reloads, from the admin HTTP endpoint and on SIGHUP.
+#[derive(Clone)]
+pub struct ReloadTrigger(mpsc::UnboundedSender<ReloadRequest>);
+
+impl ReloadTrigger {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<ReloadRequest>) {
+        let (requests, receiver) = mpsc::unbounded();
+        (Self(requests), receiver)
+    }
+
+    pub async fn reload(&self) -> Result<()> {
+        let (outcome, on_outcome) = oneshot::channel();
+        self.0
+            .unbounded_send(ReloadRequest(outcome))
+            .map_err(|_| anyhow!("The bridge is shutting down"))?;
+        on_outcome
+            .await
+            .map_err(|_| anyhow!("The bridge is shutting down"))?
+    }
+
+    /// Requests a reload on every SIGHUP.
+    pub fn on_hangup_signal(&self) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let trigger = self.clone();
+        actix_web::rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                // outcome is logged by the bridge
+                let _ = trigger.reload().await;
+            }
+        });
+        Ok(())
+    }
+}
+
+#[post("/admin/reload")]
+async fn reload(trigger: web::Data<ReloadTrigger>) -> impl Responder {
+    match trigger.reload().await {
+        Ok(()) => HttpResponse::Ok().body("OK"),
+        Err(error) => HttpResponse::UnprocessableEntity().body(format!("{error:#}")),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(reload);
+}
@@ -1,29 +1,165 @@
 use actix_web::rt::time;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use futures::future::{select, Either};
+use futures::{FutureExt, StreamExt};
 use policy::RepeatPolicy;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
 use std::fmt::{Debug, Display};
 use std::future::Future;
+use std::pin::pin;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use crate::circuit_breaker::CircuitBreaker;
+use crate::health::TaskMonitor;
 use crate::misc::HumanReadable;
+use crate::rate_limit::RateLimiter;
+use crate::state::PersistedTask;
 
 #[derive(Debug)]
-pub struct ExecutionFailure<E>(pub E, pub Duration);
+pub enum ExecutionFailure<E> {
+    /// The task is retried after the given delay
+    Retry(E, Duration),
+    /// The task can't succeed, it must not be executed again
+    Abort(E),
+}
 
 impl<E> Display for ExecutionFailure<E>
 where
     E: Debug,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let error = &self.0;
-        let delay = self.1.prettify();
-        write!(
-            f,
-            "postponing next retry in {delay} due to last error: {error:#?}"
-        )
+        match self {
+            ExecutionFailure::Retry(error, delay) => write!(
+                f,
+                "postponing next retry in {} due to last error: {error:#?}",
+                delay.prettify()
+            ),
+            ExecutionFailure::Abort(error) => {
+                write!(f, "giving up due to permanent error: {error:#?}")
+            }
+        }
+    }
+}
+
+/// What a failed execution tells about the next attempts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryHint {
+    /// Retry following the backoff policy
+    Backoff,
+    /// Retry once the delay requested by the remote service is over, regardless of the backoff
+    /// policy
+    After(Duration),
+    /// Retrying won't help, e.g. credentials are rejected
+    Never,
+}
+
+impl RetryHint {
+    /// Hint for an HTTP response status: rejected credentials are permanent errors.
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            401 | 403 => RetryHint::Never,
+            _ => RetryHint::Backoff,
+        }
+    }
+
+    /// Hint for an HTTP response: throttled or unavailable services are retried once the delay of
+    /// their `Retry-After` header is over.
+    pub fn from_http_response(status: StatusCode, headers: &HeaderMap) -> Self {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => headers
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, Utc::now()))
+                .map_or(RetryHint::Backoff, RetryHint::After),
+            status => RetryHint::from_http_status(status.as_u16()),
+        }
+    }
+}
+
+/// Parses a `Retry-After` header value, either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => DateTime::parse_from_rfc2822(value).ok().map(|date| {
+            (date.with_timezone(&Utc) - now)
+                .to_std()
+                .unwrap_or_default()
+        }),
     }
 }
 
+/// An HTTP error response, along with what it tells about the next attempts.
+///
+/// Unlike [reqwest::Error], it keeps the delay requested by a `Retry-After` header.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: StatusCode,
+    pub retry_hint: RetryHint,
+}
+
+impl HttpStatusError {
+    /// Turns an error response into an error, like [reqwest::Response::error_for_status].
+    pub fn check(response: reqwest::Response) -> Result<reqwest::Response, Self> {
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            Err(HttpStatusError {
+                status,
+                retry_hint: RetryHint::from_http_response(status, response.headers()),
+            })
+        } else {
+            Ok(response)
+        }
+    }
+}
+
+impl Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP status {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Errors able to tell whether and when a failed execution should be retried.
+pub trait Retryable {
+    fn retry_hint(&self) -> RetryHint {
+        RetryHint::Backoff
+    }
+}
+
+/// Cloud API errors, classified from the HTTP response found in their chain of causes.
+///
+/// [reqwest_middleware::Error] is transparent: the wrapped error doesn't appear in the chain and
+/// must be unwrapped.
+impl Retryable for anyhow::Error {
+    fn retry_hint(&self) -> RetryHint {
+        self.chain()
+            .find_map(|cause| {
+                if let Some(error) = cause.downcast_ref::<HttpStatusError>() {
+                    return Some(error.retry_hint);
+                }
+                let error = match cause.downcast_ref::<reqwest_middleware::Error>() {
+                    Some(reqwest_middleware::Error::Middleware(error)) => {
+                        return Some(error.retry_hint())
+                    }
+                    Some(reqwest_middleware::Error::Reqwest(error)) => Some(error),
+                    None => cause.downcast_ref::<reqwest::Error>(),
+                };
+                error
+                    .and_then(reqwest::Error::status)
+                    .map(|status| RetryHint::from_http_status(status.as_u16()))
+            })
+            .unwrap_or(RetryHint::Backoff)
+    }
+}
+
+/// New repeat and backoff policies for a running [RepeatableExecutor].
+pub type PolicyUpdates<RP, BP> = mpsc::UnboundedReceiver<(RP, BP)>;
+
 /// An executor to repeat a task execution periodically.
 ///
 /// Distinct execution period policies can be set for success and failures executions.
@@ -50,7 +186,8 @@ where
 /// for _ in 0..5 {
 ///     match executor.execute_next().await {
 ///         Ok(result) => println!("successful execution {result}"),
-///         Err(ExecutionFailure(error, next_delay)) => println("task will be retried in {next_delay:?}")
+///         Err(ExecutionFailure::Retry(error, next_delay)) => println("task will be retried in {next_delay:?}")
+///         Err(ExecutionFailure::Abort(error)) => break,
 ///     }
 /// }
 /// ```
@@ -68,6 +205,20 @@ where
     repeat_policy: RP,
     backoff_policy: BP,
     next_interval: Duration,
+    last_outcome: Outcome,
+    policy_updates: Option<PolicyUpdates<RP, BP>>,
+    monitor: Option<TaskMonitor>,
+    circuit_breaker: Option<CircuitBreaker>,
+    rate_limiter: Option<RateLimiter>,
+    persisted_task: Option<PersistedTask>,
+}
+
+#[derive(Default, Clone, Copy)]
+enum Outcome {
+    #[default]
+    NotExecuted,
+    Success,
+    Failure,
 }
 
 impl<RP, BP, I, E, Fn, Fut> RepeatableExecutor<TokioSleeper, RP, BP, I, E, Fn, Fut>
@@ -84,6 +235,12 @@ where
             repeat_policy: RP::default(),
             backoff_policy: BP::default(),
             next_interval: Duration::ZERO,
+            last_outcome: Outcome::NotExecuted,
+            policy_updates: None,
+            monitor: None,
+            circuit_breaker: None,
+            rate_limiter: None,
+            persisted_task: None,
         }
     }
 
@@ -98,6 +255,12 @@ where
             repeat_policy: self.repeat_policy,
             backoff_policy: self.backoff_policy,
             next_interval: self.next_interval,
+            last_outcome: self.last_outcome,
+            policy_updates: self.policy_updates,
+            monitor: self.monitor,
+            circuit_breaker: self.circuit_breaker,
+            rate_limiter: self.rate_limiter,
+            persisted_task: self.persisted_task,
         }
     }
 }
@@ -120,19 +283,135 @@ where
         self
     }
 
+    /// Report every execution outcome to the given health monitor.
+    pub fn with_monitor(mut self, monitor: TaskMonitor) -> Self {
+        self.monitor = Some(monitor);
+        self
+    }
+
+    /// Replace the policies with the ones received from `policy_updates`, even while waiting for
+    /// the next execution which is then rescheduled using the new policies.
+    pub fn with_policy_updates(mut self, policy_updates: PolicyUpdates<RP, BP>) -> Self {
+        self.policy_updates = Some(policy_updates);
+        self
+    }
+
+    /// Wait for the given circuit breaker to grant every execution and report their outcome to it.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Delay executions exceeding the requests budget of the given rate limiter.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Resume the schedule saved by a previous run and save it after every execution. Must be
+    /// called once the backoff policy is set.
+    pub fn with_persisted_task(mut self, persisted_task: PersistedTask) -> Self {
+        self.next_interval = persisted_task.initial_delay();
+        if let Some(position) = persisted_task.backoff_position() {
+            self.backoff_policy.restore_position(position);
+            self.last_outcome = if position == 0 {
+                Outcome::Success
+            } else {
+                Outcome::Failure
+            };
+        }
+        self.persisted_task = Some(persisted_task);
+        self
+    }
+
+    fn save_schedule(&self) {
+        if let Some(persisted_task) = &self.persisted_task {
+            persisted_task.save_schedule(self.next_interval, self.backoff_policy.position());
+        }
+    }
+
     /// Start next interval sleep time and execute the task.
-    pub async fn next(&mut self) -> Result<I, ExecutionFailure<E>> {
-        self.sleeper.sleep(self.next_interval).await;
-        match (self.operation)().await {
+    ///
+    /// Once an execution is aborted because of a permanent error, the task must not be executed
+    /// again.
+    pub async fn next(&mut self) -> Result<I, ExecutionFailure<E>>
+    where
+        E: Retryable,
+    {
+        self.wait_next_interval().await;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            self.sleeper.sleep(rate_limiter.reserve()).await;
+        }
+        // acquired right before the execution, a probe call is never delayed by the rate limiter
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            while let Err(delay) = circuit_breaker.try_acquire() {
+                self.sleeper.sleep(delay).await;
+            }
+        }
+        let outcome = (self.operation)().await;
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            circuit_breaker.record(outcome.is_ok());
+        }
+        match outcome {
             Ok(result) => {
+                self.last_outcome = Outcome::Success;
                 self.next_interval = self.repeat_policy.next();
                 self.backoff_policy.reset();
+                if let Some(monitor) = &self.monitor {
+                    monitor.success();
+                }
+                self.save_schedule();
                 Ok(result)
             }
             Err(error) => {
-                self.next_interval = self.backoff_policy.next();
+                self.last_outcome = Outcome::Failure;
                 self.repeat_policy.reset();
-                Err(ExecutionFailure(error, self.next_interval))
+                self.next_interval = match error.retry_hint() {
+                    RetryHint::Backoff => self.backoff_policy.next(),
+                    RetryHint::After(delay) => delay,
+                    RetryHint::Never => {
+                        if let Some(monitor) = &self.monitor {
+                            monitor.abort();
+                        }
+                        return Err(ExecutionFailure::Abort(error));
+                    }
+                };
+                if let Some(monitor) = &self.monitor {
+                    monitor.failure(self.next_interval);
+                }
+                self.save_schedule();
+                Err(ExecutionFailure::Retry(error, self.next_interval))
+            }
+        }
+    }
+
+    async fn wait_next_interval(&mut self) {
+        loop {
+            let Some(policy_updates) = self.policy_updates.as_mut() else {
+                return self.sleeper.sleep(self.next_interval).await;
+            };
+            let update = match policy_updates.next().now_or_never() {
+                Some(update) => update,
+                None => {
+                    let sleep = pin!(self.sleeper.sleep(self.next_interval));
+                    match select(sleep, policy_updates.next()).await {
+                        Either::Left(_) => return,
+                        Either::Right((update, _)) => update,
+                    }
+                }
+            };
+            match update {
+                Some((repeat_policy, backoff_policy)) => {
+                    self.repeat_policy = repeat_policy;
+                    self.backoff_policy = backoff_policy;
+                    self.next_interval = match self.last_outcome {
+                        Outcome::NotExecuted => self.next_interval,
+                        Outcome::Success => self.repeat_policy.next(),
+                        Outcome::Failure => self.backoff_policy.next(),
+                    };
+                    self.save_schedule();
+                }
+                None => self.policy_updates = None,
             }
         }
     }
@@ -180,14 +459,32 @@ mod tests {
     };
 
     use anyhow::anyhow;
+    use chrono::DateTime;
+    use futures::channel::mpsc;
     use tokio::time;
 
     use crate::repeat::{ExecutionFailure, StubSleeper};
 
     use super::{
+        parse_retry_after,
         policy::{ExponentialBackoff, FixedInterval},
-        RepeatableExecutor,
+        HttpStatusError, RepeatableExecutor, RetryHint, Retryable,
     };
+    use crate::state::StateStore;
+
+    impl Retryable for () {}
+
+    #[derive(Debug)]
+    struct HintedError(u16);
+
+    impl Retryable for HintedError {
+        fn retry_hint(&self) -> RetryHint {
+            match self.0 {
+                429 => RetryHint::After(Duration::from_secs(60)),
+                status => RetryHint::from_http_status(status),
+            }
+        }
+    }
 
     #[tokio::test]
     async fn can_schedule_repeated_successful_tasks() {
@@ -206,9 +503,7 @@ mod tests {
         for _ in 0..50 {
             match executor.next().await {
                 Ok(id) => println!("#{id} ✅"),
-                Err(ExecutionFailure((), next_delay)) => {
-                    println!("💥 retrying in {next_delay:?}")
-                }
+                Err(failure) => println!("💥 {failure}"),
             }
         }
 
@@ -229,6 +524,258 @@ mod tests {
         });
     }
 
+    #[tokio::test]
+    async fn can_restart_backoff_sequence_after_success() {
+        let stub_sleeper = StubSleeper::default();
+
+        // Given tasks failing 5 times, then succeeding twice, then failing 5 times again
+        let count = AtomicU8::new(0);
+        let task = || async {
+            let id = count.fetch_add(1, Ordering::Acquire);
+            match id {
+                0..5 | 7..12 => Err(anyhow!("E{id}")),
+                id => Ok(id),
+            }
+        };
+        let mut executor = RepeatableExecutor::new(task)
+            .with_stub_sleeper(stub_sleeper.clone())
+            .with_repeat_policy(FixedInterval::every(Duration::from_secs(60)))
+            .with_backoff_policy(ExponentialBackoff::new(
+                Duration::from_millis(100),
+                Duration::from_secs(3600),
+            ));
+
+        // When 13 tasks are executed
+        for _ in 0..13 {
+            let _ = executor.next().await;
+        }
+
+        // Then the second failure streak backs off from the initial delay again
+        let sleep_requests = stub_sleeper.requests();
+        let first_streak = &sleep_requests[1..6];
+        let second_streak = &sleep_requests[8..13];
+        assert_eq!(
+            first_streak,
+            [
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(400),
+                Duration::from_millis(800),
+                Duration::from_millis(1600),
+            ]
+        );
+        assert_eq!(
+            first_streak, second_streak,
+            "backoff restarts after a success"
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_retry_hints_of_errors() {
+        let stub_sleeper = StubSleeper::default();
+
+        // Given tasks throttled once, failing once, then rejected
+        let count = AtomicU8::new(0);
+        let task = || async {
+            match count.fetch_add(1, Ordering::Acquire) {
+                0 => Err(HintedError(429)),
+                1 => Err(HintedError(500)),
+                _ => Err(HintedError(401)),
+            }
+        };
+        let mut executor = RepeatableExecutor::<_, _, _, (), _, _, _>::new(task)
+            .with_stub_sleeper(stub_sleeper.clone())
+            .with_repeat_policy(FixedInterval::every(Duration::from_secs(600)))
+            .with_backoff_policy(ExponentialBackoff::new(
+                Duration::from_millis(100),
+                Duration::from_secs(3600),
+            ));
+
+        // When 3 tasks are executed
+        let failures = [
+            executor.next().await,
+            executor.next().await,
+            executor.next().await,
+        ];
+
+        // Then the requested delay overrides the backoff policy, and the rejection is permanent
+        assert!(matches!(
+            failures[0],
+            Err(ExecutionFailure::Retry(HintedError(429), delay)) if delay == Duration::from_secs(60)
+        ));
+        assert!(matches!(
+            failures[1],
+            Err(ExecutionFailure::Retry(HintedError(500), delay)) if delay == Duration::from_millis(100)
+        ));
+        assert!(matches!(
+            failures[2],
+            Err(ExecutionFailure::Abort(HintedError(401)))
+        ));
+        assert_eq!(
+            stub_sleeper.requests(),
+            [
+                Duration::ZERO,
+                Duration::from_secs(60),
+                Duration::from_millis(100)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn can_resume_persisted_schedule() {
+        let stub_sleeper = StubSleeper::default();
+
+        // Given a task which failed twice before a restart, due in 30 seconds
+        let store = StateStore::load(None, Duration::ZERO);
+        store.task("task").save_schedule(Duration::from_secs(30), 2);
+
+        // When it fails again after the restart
+        let task = || async { Err::<(), _>(anyhow!("E")) };
+        let mut executor = RepeatableExecutor::new(task)
+            .with_stub_sleeper(stub_sleeper.clone())
+            .with_repeat_policy(FixedInterval::every(Duration::from_secs(600)))
+            .with_backoff_policy(ExponentialBackoff::new(
+                Duration::from_millis(100),
+                Duration::from_secs(3600),
+            ))
+            .with_persisted_task(store.task("task"));
+        let _ = executor.next().await;
+        let _ = executor.next().await;
+
+        // Then the first execution waits for the saved schedule, and the backoff goes on
+        let sleep_requests = stub_sleeper.requests();
+        assert!(sleep_requests[0] > Duration::from_secs(25));
+        assert_eq!(sleep_requests[1], Duration::from_millis(400));
+        assert_eq!(store.task("task").backoff_position(), Some(4));
+    }
+
+    #[test]
+    fn classifies_http_statuses() {
+        assert_eq!(RetryHint::from_http_status(401), RetryHint::Never);
+        assert_eq!(RetryHint::from_http_status(403), RetryHint::Never);
+        assert_eq!(RetryHint::from_http_status(429), RetryHint::Backoff);
+        assert_eq!(RetryHint::from_http_status(503), RetryHint::Backoff);
+        assert_eq!(
+            anyhow::anyhow!("connection reset").retry_hint(),
+            RetryHint::Backoff
+        );
+    }
+
+    fn http_response(status: u16, retry_after: Option<&str>) -> reqwest::Response {
+        let mut response = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header("Retry-After", retry_after);
+        }
+        reqwest::Response::from(response.body("").unwrap())
+    }
+
+    #[test]
+    fn follows_retry_after_header_of_throttled_responses() {
+        let error = |status, retry_after| {
+            anyhow::Error::from(
+                HttpStatusError::check(http_response(status, retry_after)).unwrap_err(),
+            )
+        };
+
+        assert_eq!(
+            error(429, Some("120")).retry_hint(),
+            RetryHint::After(Duration::from_secs(120))
+        );
+        assert_eq!(
+            error(503, Some("5")).context("listing sites").retry_hint(),
+            RetryHint::After(Duration::from_secs(5))
+        );
+        assert_eq!(error(429, None).retry_hint(), RetryHint::Backoff);
+        assert_eq!(error(429, Some("soon")).retry_hint(), RetryHint::Backoff);
+        assert_eq!(error(500, Some("120")).retry_hint(), RetryHint::Backoff);
+        assert_eq!(error(401, None).retry_hint(), RetryHint::Never);
+        assert!(HttpStatusError::check(http_response(200, None)).is_ok());
+    }
+
+    #[test]
+    fn can_parse_retry_after_http_dates() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:27:00Z")
+            .unwrap()
+            .to_utc();
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:26:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_retry_after(" 30 ", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[test]
+    fn classifies_http_client_errors() {
+        let reqwest_error = |status| http_response(status, None).error_for_status().unwrap_err();
+
+        let error = anyhow::Error::from(reqwest_middleware::Error::Reqwest(reqwest_error(401)));
+        assert_eq!(error.retry_hint(), RetryHint::Never);
+
+        let error = anyhow::Error::from(reqwest_middleware::Error::Middleware(
+            anyhow::Error::from(reqwest_error(403)).context("refreshing token"),
+        ));
+        assert_eq!(
+            error.context("listing sites").retry_hint(),
+            RetryHint::Never
+        );
+
+        let error = anyhow::Error::from(reqwest_error(401)).context("listing stoves");
+        assert_eq!(error.retry_hint(), RetryHint::Never);
+
+        let error = anyhow::Error::from(reqwest_middleware::Error::Reqwest(reqwest_error(500)));
+        assert_eq!(error.retry_hint(), RetryHint::Backoff);
+    }
+
+    #[tokio::test]
+    async fn can_update_policies_of_a_running_executor() {
+        let stub_sleeper = StubSleeper::default();
+        let (policies, policy_updates) = mpsc::unbounded();
+
+        // Given successful tasks repeated every 500ms
+        let task = || async { Ok::<_, ()>(()) };
+        let mut executor = RepeatableExecutor::new(task)
+            .with_stub_sleeper(stub_sleeper.clone())
+            .with_repeat_policy(FixedInterval::every(Duration::from_millis(500)))
+            .with_backoff_policy(ExponentialBackoff::new(Duration::ZERO, Duration::ZERO))
+            .with_policy_updates(policy_updates);
+
+        // When 3 tasks are executed before switching to a 1s interval, then 3 more tasks
+        for _ in 0..3 {
+            executor.next().await.unwrap();
+        }
+        policies
+            .unbounded_send((
+                FixedInterval::every(Duration::from_secs(1)),
+                ExponentialBackoff::new(Duration::ZERO, Duration::ZERO),
+            ))
+            .unwrap();
+        for _ in 0..3 {
+            executor.next().await.unwrap();
+        }
+
+        // Then the pending sleep is rescheduled with the new interval
+        assert_eq!(
+            stub_sleeper.requests(),
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(500),
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(1),
+                Duration::from_secs(1),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn can_switch_to_backoff_policy_on_error() {
         let stub_sleeper = StubSleeper::default();
@@ -261,9 +808,7 @@ mod tests {
         for _ in 0..100 {
             match executor.next().await {
                 Ok(id) => print!("✅#{id}|"),
-                Err(ExecutionFailure(error, next_delay)) => {
-                    print!("💥{error}→{next_delay:?}|")
-                }
+                Err(failure) => print!("💥{failure}|"),
             }
         }
         println!();
@@ -304,14 +849,14 @@ mod tests {
         });
 
         let mut last_backoff = Duration::ZERO;
-        sleep_requests.iter().skip(31).take(15).for_each(|request| {
+        sleep_requests.iter().skip(31).take(16).for_each(|request| {
             assert!(
                 &last_backoff < request,
                 "backoff sleep request should greater than last one ({last_backoff:?}) but it was {request:?}"
             );
             last_backoff = request.clone();
         });
-        sleep_requests.iter().skip(46).take(5).for_each(|request| {
+        sleep_requests.iter().skip(47).take(5).for_each(|request| {
             assert_eq!(
                 request,
                 &Duration::from_secs(3600),
@@ -329,10 +874,16 @@ mod tests {
 }
 
 pub mod policy {
+    use chrono::{Datelike, Local, Month, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+    use chrono_tz::Tz;
     use core::ops::RangeInclusive;
     use log::warn;
     use rand::Rng;
-    use std::{cmp, fmt::Display, time::Duration, u32};
+    use std::{
+        cmp,
+        fmt::Display,
+        time::{Duration, Instant},
+    };
 
     use crate::misc::HumanReadable;
 
@@ -341,6 +892,11 @@ pub mod policy {
             Duration::ZERO
         }
         fn reset(&mut self) {}
+        /// Progress through the sequence of intervals, saved to resume it after a restart.
+        fn position(&self) -> u32 {
+            0
+        }
+        fn restore_position(&mut self, _position: u32) {}
     }
 
     #[derive(Clone)]
@@ -376,10 +932,245 @@ pub mod policy {
 
     impl RepeatPolicy for FixedInterval {
         fn next(&mut self) -> Duration {
-            if self.range.start() == self.range.end() {
-                self.range.start().clone()
+            random_duration(&self.range)
+        }
+    }
+
+    fn random_duration(range: &RangeInclusive<Duration>) -> Duration {
+        if range.start() == range.end() {
+            *range.start()
+        } else {
+            rand::thread_rng().gen_range(range.clone())
+        }
+    }
+
+    /// Months and hours of the day a specific interval applies to. Months are inclusive, the end
+    /// hour is exclusive, both may wrap around the end of the year or midnight.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct ScheduleWindow {
+        pub months: Option<(Month, Month)>,
+        pub hours: Option<(NaiveTime, NaiveTime)>,
+        pub interval: RangeInclusive<Duration>,
+    }
+
+    impl ScheduleWindow {
+        fn contains_month(&self, month: u32) -> bool {
+            self.months.is_none_or(|(start, end)| {
+                let (start, end) = (start.number_from_month(), end.number_from_month());
+                if start <= end {
+                    start <= month && month <= end
+                } else {
+                    month >= start || month <= end
+                }
+            })
+        }
+
+        fn contains_time(&self, time: NaiveTime) -> bool {
+            self.hours.is_none_or(|(start, end)| {
+                if start < end {
+                    start <= time && time < end
+                } else {
+                    start == end || time >= start || time < end
+                }
+            })
+        }
+
+        fn contains(&self, date_time: NaiveDateTime) -> bool {
+            self.contains_month(date_time.month()) && self.contains_time(date_time.time())
+        }
+    }
+
+    impl Display for ScheduleWindow {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            if let Some((start, end)) = self.months {
+                write!(f, "from {} to {} ", start.name(), end.name())?;
+            }
+            if let Some((start, end)) = self.hours {
+                write!(
+                    f,
+                    "from {} to {} ",
+                    start.format("%H:%M"),
+                    end.format("%H:%M")
+                )?;
+            }
+            write!(f, "{}", FixedInterval::between(self.interval.clone()))
+        }
+    }
+
+    /// Intervals depending on the months and hours of the day: the first matching window applies,
+    /// the default interval otherwise.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Schedule {
+        pub windows: Vec<ScheduleWindow>,
+        pub default: RangeInclusive<Duration>,
+    }
+
+    impl Schedule {
+        pub fn between(range: RangeInclusive<Duration>) -> Self {
+            Self {
+                windows: Vec::new(),
+                default: range,
+            }
+        }
+
+        /// A random interval from the window matching `now`, shortened when this window ends or
+        /// another window starts earlier.
+        fn next_interval(&self, now: NaiveDateTime) -> Duration {
+            let window = self.windows.iter().find(|window| window.contains(now));
+            let range = window.map_or(&self.default, |window| &window.interval);
+            let window_end = window.and_then(|window| window.hours).map(|(_, end)| end);
+            let window_starts = self
+                .windows
+                .iter()
+                .filter(|window| window.contains_month(now.month()))
+                .filter_map(|window| window.hours)
+                .map(|(start, _)| start);
+            window_end
+                .into_iter()
+                .chain(window_starts)
+                .map(|boundary| {
+                    let until_boundary = boundary - now.time();
+                    if until_boundary <= TimeDelta::zero() {
+                        until_boundary + TimeDelta::days(1)
+                    } else {
+                        until_boundary
+                    }
+                })
+                .filter_map(|until_boundary| until_boundary.to_std().ok())
+                .fold(random_duration(range), cmp::min)
+        }
+    }
+
+    impl Default for Schedule {
+        fn default() -> Self {
+            Self::between(Duration::ZERO..=Duration::ZERO)
+        }
+    }
+
+    impl Display for Schedule {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            for window in &self.windows {
+                write!(f, "{window}, ")?;
+            }
+            let default = FixedInterval::between(self.default.clone());
+            if self.windows.is_empty() {
+                write!(f, "{default}")
             } else {
-                rand::thread_rng().gen_range(self.range.clone())
+                write!(f, "otherwise {default}")
+            }
+        }
+    }
+
+    /// Repeat intervals following a [Schedule], evaluated in the given timezone or in the system
+    /// one.
+    #[derive(Clone, Default)]
+    pub struct ScheduledInterval {
+        schedule: Schedule,
+        timezone: Option<Tz>,
+    }
+
+    impl ScheduledInterval {
+        pub fn new(schedule: Schedule, timezone: Option<Tz>) -> Self {
+            Self { schedule, timezone }
+        }
+    }
+
+    impl Display for ScheduledInterval {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self.timezone {
+                Some(timezone) if !self.schedule.windows.is_empty() => {
+                    write!(f, "{} ({timezone} time)", self.schedule)
+                }
+                _ => write!(f, "{}", self.schedule),
+            }
+        }
+    }
+
+    impl RepeatPolicy for ScheduledInterval {
+        fn next(&mut self) -> Duration {
+            let now = match self.timezone {
+                Some(timezone) => Utc::now().with_timezone(&timezone).naive_local(),
+                None => Local::now().naive_local(),
+            };
+            self.schedule.next_interval(now)
+        }
+    }
+
+    /// Intervals of a regular policy, temporarily replaced by a short interval to follow changes
+    /// more closely.
+    #[derive(Clone, Default)]
+    pub struct AdaptiveInterval<P> {
+        regular: P,
+        fast_interval: Duration,
+        fast_until: Option<Instant>,
+    }
+
+    impl<P> AdaptiveInterval<P> {
+        pub fn new(regular: P) -> Self {
+            Self {
+                regular,
+                fast_interval: Duration::ZERO,
+                fast_until: None,
+            }
+        }
+
+        /// Repeats every `interval` until `until`, then falls back to the regular policy.
+        pub fn fast_until(mut self, interval: Duration, until: Instant) -> Self {
+            self.fast_interval = interval;
+            self.fast_until = Some(until);
+            self
+        }
+    }
+
+    impl<P: Display> Display for AdaptiveInterval<P> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self.fast_until {
+                Some(until) if until > Instant::now() => write!(
+                    f,
+                    "every {} for {}, then {}",
+                    self.fast_interval.prettify(),
+                    (until - Instant::now()).prettify(),
+                    self.regular
+                ),
+                _ => write!(f, "{}", self.regular),
+            }
+        }
+    }
+
+    impl<P: RepeatPolicy> RepeatPolicy for AdaptiveInterval<P> {
+        fn next(&mut self) -> Duration {
+            match self.fast_until {
+                Some(until) if Instant::now() < until => self.fast_interval,
+                _ => {
+                    self.fast_until = None;
+                    self.regular.next()
+                }
+            }
+        }
+
+        fn reset(&mut self) {
+            self.regular.reset();
+        }
+    }
+
+    /// Randomization of the backoff delays, to avoid synchronized retries of distinct tasks.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub enum Jitter {
+        /// Delays double on every attempt
+        #[default]
+        None,
+        /// Random delays between zero and the exponential delay
+        Full,
+        /// Random delays between the initial delay and three times the previous delay
+        Decorrelated,
+    }
+
+    impl Display for Jitter {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Jitter::None => write!(f, "none"),
+                Jitter::Full => write!(f, "full"),
+                Jitter::Decorrelated => write!(f, "decorrelated"),
             }
         }
     }
@@ -388,7 +1179,9 @@ pub mod policy {
     pub struct ExponentialBackoff {
         initial_delay: Duration,
         max_delay: Duration,
-        attempts: exponential_backoff::IntoIter,
+        jitter: Jitter,
+        attempts: u32,
+        last_delay: Duration,
     }
 
     impl Default for ExponentialBackoff {
@@ -405,45 +1198,94 @@ pub mod policy {
                 )
             }
             let max_delay = cmp::max(initial_delay, max_delay);
-            let backoff = exponential_backoff::Backoff::new(u32::MAX, initial_delay, max_delay);
             ExponentialBackoff {
                 initial_delay,
                 max_delay,
-                attempts: backoff.into_iter(),
+                jitter: Jitter::None,
+                attempts: 0,
+                last_delay: initial_delay,
             }
         }
+
+        pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+            self.jitter = jitter;
+            self
+        }
+
+        fn exponential_delay(&self, attempts: u32) -> Duration {
+            2u32.checked_pow(attempts)
+                .and_then(|factor| self.initial_delay.checked_mul(factor))
+                .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay))
+        }
     }
 
     impl Display for ExponentialBackoff {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             let initial = self.initial_delay.prettify();
             let max = self.max_delay.prettify();
-            write!(f, "exponential backoff from {initial} up to {max}")
+            match self.jitter {
+                Jitter::None => write!(f, "exponential backoff from {initial} up to {max}"),
+                jitter => write!(
+                    f,
+                    "exponential backoff from {initial} up to {max} with {jitter} jitter"
+                ),
+            }
         }
     }
 
     impl RepeatPolicy for ExponentialBackoff {
         fn next(&mut self) -> Duration {
-            match self.attempts.next() {
-                Some(Some(delay)) => delay,
-                _ => self.max_delay.clone(),
-            }
+            let delay = match self.jitter {
+                Jitter::None => self.exponential_delay(self.attempts),
+                Jitter::Full => rand::thread_rng()
+                    .gen_range(Duration::ZERO..=self.exponential_delay(self.attempts)),
+                Jitter::Decorrelated => {
+                    let upper_bound =
+                        cmp::max(self.initial_delay, self.last_delay.saturating_mul(3));
+                    let delay = rand::thread_rng().gen_range(self.initial_delay..=upper_bound);
+                    cmp::min(delay, self.max_delay)
+                }
+            };
+            self.attempts = self.attempts.saturating_add(1);
+            self.last_delay = delay;
+            delay
         }
 
         fn reset(&mut self) {
-            //let backoff =
-            //    exponential_backoff::Backoff::new(u32::MAX, self.initial_delay, self.max_delay);
-            // self.attempts = backoff.into_iter();
+            self.attempts = 0;
+            self.last_delay = self.initial_delay;
+        }
+
+        fn position(&self) -> u32 {
+            self.attempts
+        }
+
+        fn restore_position(&mut self, position: u32) {
+            self.attempts = position;
+            self.last_delay = self.exponential_delay(position.saturating_sub(1));
         }
     }
 
     #[cfg(test)]
     mod tests {
-        use std::time::Duration;
+        use std::time::{Duration, Instant};
+
+        use chrono::{Month, NaiveDate, NaiveDateTime, NaiveTime};
 
-        use crate::repeat::policy::{ExponentialBackoff, RepeatPolicy};
+        use crate::repeat::policy::{ExponentialBackoff, Jitter, RepeatPolicy};
 
-        use super::FixedInterval;
+        use super::{AdaptiveInterval, FixedInterval, Schedule, ScheduleWindow};
+
+        fn at(month: u32, hour: u32, minute: u32) -> NaiveDateTime {
+            NaiveDate::from_ymd_opt(2024, month, 15)
+                .unwrap()
+                .and_hms_opt(hour, minute, 0)
+                .unwrap()
+        }
+
+        fn minutes(minutes: u64) -> Duration {
+            Duration::from_secs(minutes * 60)
+        }
 
         #[test]
         fn fixed_interval_policy_generation() {
@@ -452,6 +1294,26 @@ pub mod policy {
             assert_eq!(policy.next(), Duration::from_secs(10))
         }
 
+        #[test]
+        fn adaptive_interval_policy_generation() {
+            let regular = FixedInterval::every(minutes(10));
+
+            let mut policy = AdaptiveInterval::new(regular.clone());
+            assert_eq!(policy.next(), minutes(10));
+
+            let mut policy = AdaptiveInterval::new(regular.clone())
+                .fast_until(Duration::from_secs(30), Instant::now() + minutes(5));
+            assert_eq!(policy.next(), Duration::from_secs(30));
+            assert_eq!(policy.next(), Duration::from_secs(30));
+
+            let mut policy = AdaptiveInterval::new(regular).fast_until(minutes(1), Instant::now());
+            assert_eq!(
+                policy.next(),
+                minutes(10),
+                "regular interval applies once the window is over"
+            );
+        }
+
         #[test]
         fn random_interval_policy_generation() {
             let accepted_range = Duration::from_secs(90)..=Duration::from_secs(110);
@@ -466,12 +1328,71 @@ pub mod policy {
             }
         }
 
+        #[test]
+        fn scheduled_interval_generation() {
+            // Every minute on heating season evenings, every 30 minutes otherwise
+            let schedule = Schedule {
+                windows: vec![ScheduleWindow {
+                    months: Some((Month::October, Month::April)),
+                    hours: Some((
+                        NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+                        NaiveTime::from_hms_opt(23, 0, 0).unwrap(),
+                    )),
+                    interval: minutes(1)..=minutes(1),
+                }],
+                default: minutes(30)..=minutes(30),
+            };
+
+            assert_eq!(schedule.next_interval(at(12, 20, 0)), minutes(1));
+            assert_eq!(schedule.next_interval(at(2, 18, 0)), minutes(1));
+            assert_eq!(schedule.next_interval(at(12, 23, 0)), minutes(30));
+            assert_eq!(schedule.next_interval(at(7, 20, 0)), minutes(30));
+            assert_eq!(
+                schedule.next_interval(at(12, 17, 50)),
+                minutes(10),
+                "interval is shortened to the start of the next window"
+            );
+            assert_eq!(
+                schedule.next_interval(at(7, 17, 50)),
+                minutes(30),
+                "windows of other months are ignored"
+            );
+        }
+
+        #[test]
+        fn scheduled_interval_windows_wrap_around_midnight() {
+            let schedule = Schedule {
+                windows: vec![ScheduleWindow {
+                    months: None,
+                    hours: Some((
+                        NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+                        NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+                    )),
+                    interval: minutes(60)..=minutes(60),
+                }],
+                default: minutes(5)..=minutes(5),
+            };
+
+            assert_eq!(schedule.next_interval(at(1, 23, 0)), minutes(60));
+            assert_eq!(
+                schedule.next_interval(at(1, 5, 30)),
+                minutes(30),
+                "interval is shortened to the end of the window"
+            );
+            assert_eq!(schedule.next_interval(at(1, 6, 0)), minutes(5));
+        }
+
         #[test]
         fn exponential_backoff_policy_generation() {
             let mut policy =
                 ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(180));
             let mut previous = policy.next();
-            for _ in 0..=9 {
+            assert_eq!(
+                previous,
+                Duration::from_millis(100),
+                "first backoff iteration waits 'initial_delay'"
+            );
+            for _ in 0..=10 {
                 let next = policy.next();
                 assert!(previous < next, "Each backoff iteration increase delay");
                 previous = next;
@@ -492,5 +1413,64 @@ pub mod policy {
                 "backoff interations never exceeds 'max_delay'"
             );
         }
+
+        #[test]
+        fn exponential_backoff_policy_restore_position() {
+            let mut policy =
+                ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(3600));
+            policy.next();
+            policy.next();
+            assert_eq!(policy.position(), 2);
+
+            let mut restored =
+                ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(3600));
+            restored.restore_position(policy.position());
+            assert_eq!(restored.next(), policy.next());
+        }
+
+        #[test]
+        fn exponential_backoff_policy_reset() {
+            let mut policy =
+                ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(180));
+            let first_attempts: Vec<Duration> = (0..5).map(|_| policy.next()).collect();
+            policy.reset();
+            let attempts_after_reset: Vec<Duration> = (0..5).map(|_| policy.next()).collect();
+            assert_eq!(
+                first_attempts, attempts_after_reset,
+                "delays restart from the initial delay"
+            );
+        }
+
+        #[test]
+        fn exponential_backoff_policy_full_jitter() {
+            let mut policy =
+                ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(180))
+                    .with_jitter(Jitter::Full);
+            let mut exponential_policy =
+                ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(180));
+            for _ in 0..20 {
+                let next = policy.next();
+                let max = exponential_policy.next();
+                assert!(next <= max, "{next:?} should not exceed {max:?}");
+            }
+        }
+
+        #[test]
+        fn exponential_backoff_policy_decorrelated_jitter() {
+            let initial = Duration::from_millis(100);
+            let max = Duration::from_secs(180);
+            let mut policy =
+                ExponentialBackoff::new(initial, max).with_jitter(Jitter::Decorrelated);
+            let mut previous = initial;
+            for _ in 0..100 {
+                let next = policy.next();
+                let upper_bound = std::cmp::min(previous * 3, max);
+                assert!(
+                    initial <= next && next <= upper_bound,
+                    "{next:?} should be between {initial:?} and {upper_bound:?}"
+                );
+                previous = next;
+            }
+        }
     }
 }
@@ -1,18 +1,32 @@
 use crate::{
-    misc::{app_infos, Sluggable},
+    circuit_breaker::CircuitBreaker,
+    health::HealthRegistry,
+    metrics,
+    misc::{app_infos, HumanReadable, Sluggable},
     mqtt::{
-        EntityConfiguration, HaMqttEntity, MqttActor, MqttMessage, PublishEntityData, Subscribe,
+        EntityConfiguration, HaMqttEntity, MqttActor, MqttMessage, PublishEntityData, RemoveDevice,
+        Subscribe, Topics, STALE_ATTRIBUTES_TEMPLATE,
     },
+    problem::ProblemSensor,
+    rate_limit::RateLimiter,
     repeat::{
-        policy::{ExponentialBackoff, FixedInterval},
-        RepeatableExecutor,
+        policy::{
+            AdaptiveInterval, ExponentialBackoff, FixedInterval, Jitter, Schedule,
+            ScheduledInterval,
+        },
+        ExecutionFailure, RepeatableExecutor,
     },
+    shutdown::{Shutdown, Stop},
+    state::{PersistedTask, StateStore},
 };
 use actix::prelude::*;
+use actix_web::rt::time;
 use anyhow::{bail, Result};
 use async_stream::stream;
 use chrono::TimeDelta;
+use chrono_tz::Tz;
 use derive_new::new;
+use futures::{channel::mpsc, future::join_all};
 use ha_mqtt_discovery::{
     mqtt::{
         climate::Climate,
@@ -37,34 +51,126 @@ use rika_firenet_client::{HasDetailledStatus, StoveControls};
 use rika_firenet_client::{RikaFirenetClient, StoveStatus};
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use std::{fmt::Display, ops::RangeInclusive, time::Duration, vec};
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+    vec,
+};
 
 lazy_static! {
     static ref RIKA_SENSOR_EXPIRATION_TIME: TimeDelta = TimeDelta::minutes(2);
     static ref DEDUPLICATE_COMMANDS_GRACE_TIME: TimeDelta = TimeDelta::seconds(2);
 }
 
+/// Stove statuses announcing further changes within the next minutes.
+const TRANSITION_STATUSES: [&str; 3] = ["Ignition", "Startup", "Burnout"];
+
 const COMMON_BASE_TOPIC: &str = "rika-firenet";
 
 #[derive(Clone)]
 pub struct StoveDiscoveryActorConfiguration {
+    /// Name of the account, [None] for the unnamed account configured by the command line
+    pub account: Option<String>,
     pub stove_discovery_repeat_interval: RangeInclusive<Duration>,
     pub stove_discovery_backoff_ceil: Duration,
-    pub stove_status_repeat_interval: RangeInclusive<Duration>,
+    pub stove_status_repeat_schedule: Schedule,
     pub stove_status_backoff_ceil: Duration,
+    /// Status update interval following a command or a status transition
+    pub stove_status_fast_repeat_interval: Duration,
+    /// Duration of the fast status updates, zero to disable them
+    pub stove_status_fast_repeat_window: Duration,
+    pub backoff_jitter: Jitter,
+    /// Timezone of the schedules, [None] for the system one
+    pub timezone: Option<Tz>,
+    pub missing_device_removal_threshold: u32,
+    /// API requests budget of the account, 0 for no limit
+    pub requests_per_hour: u32,
+    pub topics: Topics,
+}
+
+impl StoveDiscoveryActorConfiguration {
+    /// Stoves of named accounts are published under their own sub-topic so that stoves of
+    /// different accounts never collide.
+    fn base_topic(&self) -> String {
+        match &self.account {
+            Some(account) => self
+                .topics
+                .namespaced(&format!("{COMMON_BASE_TOPIC}/{}", account.slug())),
+            None => self.topics.namespaced(COMMON_BASE_TOPIC),
+        }
+    }
+
+    fn task_name(&self, task: &str) -> String {
+        match &self.account {
+            Some(account) => format!("rika/{account}/{task}"),
+            None => format!("rika/{task}"),
+        }
+    }
+
+    fn display_name(&self) -> String {
+        match &self.account {
+            Some(account) => format!("Rika Firenet account {account}"),
+            None => "Rika Firenet".to_string(),
+        }
+    }
+
+    fn stove_discovery_policies(&self) -> (FixedInterval, ExponentialBackoff) {
+        (
+            FixedInterval::between(self.stove_discovery_repeat_interval.clone()),
+            ExponentialBackoff::new(Duration::from_secs(5), self.stove_discovery_backoff_ceil)
+                .with_jitter(self.backoff_jitter),
+        )
+    }
+
+    fn stove_status_policies(&self) -> (AdaptiveInterval<ScheduledInterval>, ExponentialBackoff) {
+        (
+            AdaptiveInterval::new(ScheduledInterval::new(
+                self.stove_status_repeat_schedule.clone(),
+                self.timezone,
+            )),
+            ExponentialBackoff::new(Duration::from_millis(500), self.stove_status_backoff_ceil)
+                .with_jitter(self.backoff_jitter),
+        )
+    }
 }
 
+/// Senders of new policies to the [RepeatableExecutor] of a running actor.
+type PolicyUpdater<RP> = mpsc::UnboundedSender<(RP, ExponentialBackoff)>;
+
+/// Applies a new configuration to running actors, in place. Changes of the account or of the
+/// topics are ignored.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reconfigure(pub StoveDiscoveryActorConfiguration);
+
 pub struct StoveDiscoveryActor {
     config: StoveDiscoveryActorConfiguration,
     mqtt_addr: Addr<MqttActor>,
     rika_client: RikaFirenetClient,
+    health: HealthRegistry,
+    circuit_breaker: CircuitBreaker,
+    rate_limiter: RateLimiter,
+    problem: ProblemSensor,
+    state: StateStore,
+    discovery_task: PersistedTask,
     stoves: Vec<RunningStoveActor>,
+    /// Stoves whose actor is being initialized
+    starting_stove_ids: BTreeSet<String>,
+    discovery_policies: Option<PolicyUpdater<FixedInterval>>,
 }
 
 #[derive(new)]
 struct RunningStoveActor {
+    stove_id: String,
     topic_prefix: String,
     addr: Addr<StoveActor>,
+    /// Started from the state saved by a previous run, not listed by a discovery yet
+    restored: bool,
+    #[new(default)]
+    missed_discoveries: u32,
 }
 
 impl StoveDiscoveryActor {
@@ -72,12 +178,28 @@ impl StoveDiscoveryActor {
         configuration: C,
         mqtt_addr: Addr<MqttActor>,
         rika_client: RikaFirenetClient,
+        health: HealthRegistry,
+        circuit_breaker: CircuitBreaker,
+        state: StateStore,
     ) -> Self {
+        let config: StoveDiscoveryActorConfiguration = configuration.into();
         StoveDiscoveryActor {
-            config: configuration.into(),
+            discovery_task: state.task(config.task_name("stoves-discovery")),
+            state,
+            rate_limiter: RateLimiter::new(config.display_name(), config.requests_per_hour),
+            problem: ProblemSensor::new(
+                config.display_name(),
+                config.topics.clone(),
+                mqtt_addr.clone(),
+            ),
+            config,
             mqtt_addr,
             rika_client,
+            health,
+            circuit_breaker,
             stoves: Vec::new(),
+            starting_stove_ids: BTreeSet::new(),
+            discovery_policies: None,
         }
     }
 
@@ -99,6 +221,98 @@ impl StoveDiscoveryActor {
         .into_actor(act)
         .spawn(ctx);
     }
+
+    fn start_stove_actor(&mut self, stove_id: String, restored: bool, ctx: &mut Context<Self>) {
+        if self.stoves.iter().any(|stove| stove.stove_id == stove_id)
+            || !self.starting_stove_ids.insert(stove_id.clone())
+        {
+            return;
+        }
+        info!("Found stove id {stove_id}");
+        let config = self.config.clone();
+        let mqtt_addr = self.mqtt_addr.clone();
+        let client = self.rika_client.clone();
+        let health = self.health.clone();
+        let circuit_breaker = self.circuit_breaker.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let problem = self.problem.clone();
+        let state = self.state.clone();
+        let requested_stove_id = stove_id.clone();
+        async move {
+            StoveActor::new(
+                config,
+                mqtt_addr,
+                client,
+                health,
+                circuit_breaker,
+                rate_limiter,
+                problem,
+                state,
+                requested_stove_id,
+            )
+            .await
+        }
+        .into_actor(self)
+        .map(move |stove_actor, act, _ctx| {
+            act.starting_stove_ids.remove(&stove_id);
+            match stove_actor {
+                Ok(stove_actor) => {
+                    let topic_prefix = stove_actor.topic_prefix.clone();
+                    let addr = stove_actor.start();
+                    act.stoves.push(RunningStoveActor::new(
+                        stove_id,
+                        topic_prefix,
+                        addr,
+                        restored,
+                    ));
+                }
+                Err(error) => {
+                    error!("Can't initialize actor for stove id={stove_id}: {error}")
+                }
+            };
+        })
+        .spawn(ctx);
+    }
+
+    /// Stops actors of stoves missing from too many consecutive discoveries and removes their
+    /// entities from Home Assistant. Stoves restored from the saved state are removed as soon as
+    /// a discovery doesn't list them.
+    fn remove_missing_stoves(&mut self, discovered_stove_ids: &[String]) {
+        let (unknown_stoves, known_stoves): (Vec<_>, Vec<_>) = self
+            .stoves
+            .drain(..)
+            .partition(|stove| stove.restored && !discovered_stove_ids.contains(&stove.stove_id));
+        self.stoves = known_stoves;
+        for stove in unknown_stoves {
+            info!("Removing stove id {}, no longer listed", stove.stove_id);
+            stove.addr.do_send(RemoveStove);
+        }
+        for stove in self.stoves.iter_mut() {
+            if discovered_stove_ids.contains(&stove.stove_id) {
+                stove.restored = false;
+                stove.missed_discoveries = 0;
+            } else {
+                stove.missed_discoveries += 1;
+                warn!(
+                    "Stove id {} is missing from the last {} discoveries",
+                    stove.stove_id, stove.missed_discoveries
+                );
+            }
+        }
+        let threshold = self.config.missing_device_removal_threshold;
+        if threshold == 0 {
+            return;
+        }
+        let (missing_stoves, running_stoves) = self
+            .stoves
+            .drain(..)
+            .partition(|stove| stove.missed_discoveries >= threshold);
+        self.stoves = running_stoves;
+        for stove in missing_stoves {
+            info!("Removing stove id {}", stove.stove_id);
+            stove.addr.do_send(RemoveStove);
+        }
+    }
 }
 
 impl Actor for StoveDiscoveryActor {
@@ -107,7 +321,7 @@ impl Actor for StoveDiscoveryActor {
     fn started(&mut self, ctx: &mut Self::Context) {
         // subscribe to all changes related to topics managed by this actor
         let topics_subscription_result = self.mqtt_addr.send(Subscribe::new(
-            format!("{COMMON_BASE_TOPIC}/+/+/set"),
+            format!("{}/+/+/set", self.config.base_topic()),
             ctx.address().recipient(),
         ));
         ctx.run_later(
@@ -117,29 +331,48 @@ impl Actor for StoveDiscoveryActor {
             },
         );
 
-        let repeat_policy =
-            FixedInterval::between(self.config.stove_discovery_repeat_interval.clone());
-        let backoff_policy = ExponentialBackoff::new(
-            Duration::from_secs(5),
-            self.config.stove_discovery_backoff_ceil,
-        );
+        // stoves found by the previous run are known until the next discovery
+        for stove_id in self
+            .discovery_task
+            .data::<Vec<String>>()
+            .unwrap_or_default()
+        {
+            self.start_stove_actor(stove_id, true, ctx);
+        }
+
+        let (repeat_policy, backoff_policy) = self.config.stove_discovery_policies();
         info!("Scheduling stoves discovery using policy {repeat_policy} and {backoff_policy}");
 
         let client = self.rika_client.clone();
+        let monitor = self.health.task(self.config.task_name("stoves-discovery"));
+        let circuit_breaker = self.circuit_breaker.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let problem = self.problem.clone();
+        let persisted_task = self.discovery_task.clone();
+        let (policies, policy_updates) = mpsc::unbounded();
+        self.discovery_policies = Some(policies);
         ctx.add_stream(stream! {
             let list_stoves = || async {
-                  client.list_stoves().await
+                  metrics::observe_api_call("rika", "list_stoves", client.list_stoves())
+                      .await
+                      .map_err(anyhow::Error::from)
             };
             let mut executor = RepeatableExecutor::new(list_stoves)
                 .with_repeat_policy(repeat_policy)
-                .with_backoff_policy(backoff_policy);
+                .with_backoff_policy(backoff_policy)
+                .with_policy_updates(policy_updates)
+                .with_monitor(monitor)
+                .with_circuit_breaker(circuit_breaker)
+                .with_rate_limiter(rate_limiter)
+                .with_persisted_task(persisted_task);
 
             loop {
                 match executor.next().await {
-                    Ok(stove_ids) => {
-                        for stove_id in stove_ids {
-                            yield StoveDiscovered::new(stove_id);
-                        }
+                    Ok(stove_ids) => yield StovesDiscovered::new(stove_ids),
+                    Err(ExecutionFailure::Abort(error)) => {
+                        error!("Stopped discovering stoves: {error:#}");
+                        problem.raise(format!("{error:#}"));
+                        break;
                     }
                     Err(execution_failure) => error!("Unable to discover available stoves: {execution_failure}"),
                 }
@@ -149,32 +382,86 @@ impl Actor for StoveDiscoveryActor {
 }
 
 #[derive(new)]
-struct StoveDiscovered {
-    id: String,
+struct StovesDiscovered {
+    ids: Vec<String>,
 }
 
-impl StreamHandler<StoveDiscovered> for StoveDiscoveryActor {
-    fn handle(&mut self, stove: StoveDiscovered, ctx: &mut Self::Context) {
-        let stove_id = stove.id.clone();
-        info!("Found stove id {stove_id}");
-        let config = self.config.clone();
-        let mqtt_addr = self.mqtt_addr.clone();
-        let client = self.rika_client.clone();
-        async move { StoveActor::new(config, mqtt_addr, client, stove_id).await }
-            .into_actor(self)
-            .map(move |stove_actor, act, _ctx| {
-                match stove_actor {
-                    Ok(stove_actor) => {
-                        let topic_prefix = stove_actor.topic_prefix.clone();
-                        let addr = stove_actor.start();
-                        act.stoves.push(RunningStoveActor::new(topic_prefix, addr));
-                    }
-                    Err(error) => {
-                        error!("Can't initialize actor for stove id={}: {error}", stove.id)
-                    }
-                };
-            })
-            .spawn(ctx);
+impl StreamHandler<StovesDiscovered> for StoveDiscoveryActor {
+    fn handle(&mut self, stoves: StovesDiscovered, ctx: &mut Self::Context) {
+        self.discovery_task.save_data(&stoves.ids);
+        self.remove_missing_stoves(&stoves.ids);
+        for stove_id in stoves.ids {
+            self.start_stove_actor(stove_id, false, ctx);
+        }
+    }
+
+    fn finished(&mut self, _ctx: &mut Self::Context) {
+        // override default behavior to keep the actor running
+    }
+}
+
+impl Handler<Shutdown> for StoveDiscoveryActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        let stoves: Vec<_> = self
+            .stoves
+            .iter()
+            .map(|stove| stove.addr.send(Shutdown))
+            .collect();
+        Box::pin(async move {
+            join_all(stoves).await;
+        })
+    }
+}
+
+impl Handler<Reconfigure> for StoveDiscoveryActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reconfigure, _ctx: &mut Self::Context) -> Self::Result {
+        let Reconfigure(config) = msg;
+        let old_policies = (
+            &self.config.stove_discovery_repeat_interval,
+            self.config.stove_discovery_backoff_ceil,
+            self.config.backoff_jitter,
+        );
+        let new_policies = (
+            &config.stove_discovery_repeat_interval,
+            config.stove_discovery_backoff_ceil,
+            config.backoff_jitter,
+        );
+        if old_policies != new_policies {
+            let (repeat_policy, backoff_policy) = config.stove_discovery_policies();
+            info!(
+                "Rescheduling stoves discovery using policy {repeat_policy} and {backoff_policy}"
+            );
+            if let Some(policies) = &self.discovery_policies {
+                let _ = policies.unbounded_send((repeat_policy, backoff_policy));
+            }
+        }
+        self.rate_limiter
+            .set_requests_per_hour(config.requests_per_hour);
+        for stove in &self.stoves {
+            stove.addr.do_send(Reconfigure(config.clone()));
+        }
+        self.config = StoveDiscoveryActorConfiguration {
+            account: self.config.account.clone(),
+            topics: self.config.topics.clone(),
+            ..config
+        };
+    }
+}
+
+impl Handler<Stop> for StoveDiscoveryActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Stop, ctx: &mut Self::Context) -> Self::Result {
+        for stove in self.stoves.drain(..) {
+            stove.addr.do_send(Stop);
+        }
+        self.health
+            .remove_task(&self.config.task_name("stoves-discovery"));
+        ctx.stop();
     }
 }
 
@@ -182,7 +469,7 @@ impl Handler<MqttMessage> for StoveDiscoveryActor {
     type Result = ();
 
     fn handle(&mut self, msg: MqttMessage, _ctx: &mut Self::Context) -> Self::Result {
-        match RikaFirenetCommand::try_from(msg) {
+        match RikaFirenetCommand::parse(msg, &self.config.base_topic()) {
             Ok(RikaFirenetCommand {
                 topic_prefix,
                 command,
@@ -204,9 +491,20 @@ struct StoveActor {
     config: StoveDiscoveryActorConfiguration,
     mqtt_addr: Addr<MqttActor>,
     rika_firenet_client: RikaFirenetClient,
+    health: HealthRegistry,
+    circuit_breaker: CircuitBreaker,
+    rate_limiter: RateLimiter,
+    problem: ProblemSensor,
+    status_task: PersistedTask,
     topic_prefix: String,
     last_status: StoveStatus,
+    /// The last status was saved by a previous run
+    last_status_stale: bool,
     pending_commands: Vec<StoveCommand>,
+    published_entities: Vec<RikaEntities>,
+    status_policies: Option<PolicyUpdater<AdaptiveInterval<ScheduledInterval>>>,
+    /// End of the fast status updates window, kept across reconfigurations
+    fast_until: Option<Instant>,
 }
 
 impl StoveActor {
@@ -214,19 +512,91 @@ impl StoveActor {
         config: StoveDiscoveryActorConfiguration,
         mqtt_addr: Addr<MqttActor>,
         rika_firenet_client: RikaFirenetClient,
+        health: HealthRegistry,
+        circuit_breaker: CircuitBreaker,
+        rate_limiter: RateLimiter,
+        problem: ProblemSensor,
+        state: StateStore,
         stove_id: String,
     ) -> Result<Self> {
-        let last_status = rika_firenet_client.status(stove_id).await?;
-        let StoveMetadata { topic_prefix, .. } = (&last_status).into();
+        let status_task = state.task(config.task_name(&format!("stove/{stove_id}/status")));
+        let cached_status = status_task.data::<StoveStatus>();
+        let last_status_stale = cached_status.is_some();
+        let last_status = match cached_status {
+            Some(cached_status) => cached_status,
+            None => {
+                time::sleep(status_task.initial_delay()).await;
+                time::sleep(rate_limiter.reserve()).await;
+                metrics::observe_api_call("rika", "status", rika_firenet_client.status(stove_id))
+                    .await?
+            }
+        };
+        let StoveMetadata { topic_prefix, .. } =
+            StoveMetadata::new(&last_status, &config.base_topic());
         Ok(StoveActor {
             config,
             mqtt_addr,
             rika_firenet_client,
+            health,
+            circuit_breaker,
+            rate_limiter,
+            problem,
+            status_task,
             topic_prefix,
             last_status,
+            last_status_stale,
             pending_commands: Vec::new(),
+            published_entities: Vec::new(),
+            status_policies: None,
+            fast_until: None,
         })
     }
+
+    fn status_task_name(&self) -> String {
+        self.config
+            .task_name(&format!("stove/{}/status", self.last_status.stove_id))
+    }
+
+    /// Status policies of the given configuration, fast until the end of the current window.
+    fn status_policies(
+        &self,
+        config: &StoveDiscoveryActorConfiguration,
+    ) -> (AdaptiveInterval<ScheduledInterval>, ExponentialBackoff) {
+        let (repeat_policy, backoff_policy) = config.stove_status_policies();
+        match self.fast_until {
+            Some(until) if !config.stove_status_fast_repeat_window.is_zero() => (
+                repeat_policy.fast_until(config.stove_status_fast_repeat_interval, until),
+                backoff_policy,
+            ),
+            _ => (repeat_policy, backoff_policy),
+        }
+    }
+
+    /// Updates the status at the fast interval for a while, to follow the stove reaction.
+    fn poll_faster(&mut self, reason: &str) {
+        let window = self.config.stove_status_fast_repeat_window;
+        if window.is_zero() {
+            return;
+        }
+        self.fast_until = Some(Instant::now() + window);
+        let (repeat_policy, backoff_policy) = self.status_policies(&self.config);
+        info!(
+            "Scheduling stove id {} data update {repeat_policy} after {reason}",
+            self.last_status.stove_id
+        );
+        if let Some(policies) = &self.status_policies {
+            let _ = policies.unbounded_send((repeat_policy, backoff_policy));
+        }
+    }
+
+    fn publish_configurations(&mut self, entities: RikaEntities) {
+        if !self.published_entities.contains(&entities) {
+            self.published_entities.push(entities.clone());
+        }
+        for entity in entities.list_entities() {
+            self.mqtt_addr.do_send(EntityConfiguration(entity));
+        }
+    }
 }
 
 impl Actor for StoveActor {
@@ -236,32 +606,51 @@ impl Actor for StoveActor {
         let stove_id = self.last_status.stove_id.clone();
         let client = self.rika_firenet_client.clone();
 
-        let repeat_policy =
-            FixedInterval::between(self.config.stove_status_repeat_interval.clone());
-        let backoff_policy = ExponentialBackoff::new(
-            Duration::from_millis(500),
-            self.config.stove_status_backoff_ceil,
-        );
+        let (repeat_policy, backoff_policy) = self.config.stove_status_policies();
         info!("Scheduling stove id {stove_id} data update using policy {repeat_policy} and {backoff_policy}");
 
-        for entity in RikaEntities::from(&self.last_status).list_entities() {
-            self.mqtt_addr.do_send(EntityConfiguration(entity));
+        let entities = RikaEntities::new(&self.last_status, &self.config);
+        if self.last_status_stale {
+            info!("Publishing last known status of stove id={stove_id}");
+            for data_payload in entities.status_payloads(&self.last_status, true) {
+                self.mqtt_addr.do_send(data_payload);
+            }
         }
+        self.publish_configurations(entities);
 
+        let monitor = self.health.task(self.status_task_name());
+        let circuit_breaker = self.circuit_breaker.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let problem = self.problem.clone();
+        let persisted_task = self.status_task.clone();
+        let (policies, policy_updates) = mpsc::unbounded();
+        self.status_policies = Some(policies);
         ctx.add_stream(stream! {
             let fetch_stove_status = || async {
-                 client.status(&stove_id).await
+                 metrics::observe_api_call("rika", "status", client.status(&stove_id))
+                     .await
+                     .map_err(anyhow::Error::from)
             };
 
             let mut executor = RepeatableExecutor::new(fetch_stove_status)
                 .with_repeat_policy(repeat_policy)
-                .with_backoff_policy(backoff_policy);
+                .with_backoff_policy(backoff_policy)
+                .with_policy_updates(policy_updates)
+                .with_monitor(monitor)
+                .with_circuit_breaker(circuit_breaker)
+                .with_rate_limiter(rate_limiter)
+                .with_persisted_task(persisted_task);
 
             loop {
                 match executor.next().await {
                     Ok(status) => {
                         yield status;
                     }
+                    Err(ExecutionFailure::Abort(error)) => {
+                        error!("Stopped fetching status for stove id={stove_id}: {error:#}");
+                        problem.raise(format!("{error:#}"));
+                        break;
+                    }
                     Err(execution_failure) => error!("Unable to fetch status for stove id={stove_id}: {execution_failure}"),
                 }
             }
@@ -272,27 +661,202 @@ impl Actor for StoveActor {
 impl StreamHandler<StoveStatus> for StoveActor {
     fn handle(&mut self, stove_status: StoveStatus, _ctx: &mut Self::Context) {
         let stove_id = stove_status.stove_id.clone();
-        let old_entities = RikaEntities::from(&self.last_status);
-        let new_entities = RikaEntities::from(&stove_status);
+        let old_entities = RikaEntities::new(&self.last_status, &self.config);
+        let new_entities = RikaEntities::new(&stove_status, &self.config);
 
         trace!("Publishing status data for stove id={stove_id}: {stove_status:?}");
-        for data_payload in new_entities.build_payloads(stove_status) {
+        for data_payload in new_entities.status_payloads(&stove_status, false) {
             self.mqtt_addr.do_send(data_payload);
         }
+        self.status_task.save_data(&stove_status);
 
         if new_entities != old_entities {
             trace!("Publishing configurations for stove id={stove_id}:\n{new_entities}");
-            for entity in new_entities.list_entities() {
-                self.mqtt_addr.do_send(EntityConfiguration(entity));
+            self.publish_configurations(new_entities);
+        }
+
+        let old_status_details = serde_json::to_value(self.last_status.get_status_details()).ok();
+        let new_status_details = serde_json::to_value(stove_status.get_status_details()).ok();
+        self.last_status = stove_status;
+        self.last_status_stale = false;
+        if let Some(status) = entered_transition(&old_status_details, &new_status_details) {
+            self.poll_faster(&format!("{status} status"));
+        }
+    }
+
+    fn finished(&mut self, _ctx: &mut Self::Context) {
+        // override default behavior to keep the actor running, command results are streamed too
+    }
+}
+
+/// Transition status the stove entered, given its status details before and after an update.
+fn entered_transition<'a>(
+    old_status_details: &Option<Value>,
+    new_status_details: &'a Option<Value>,
+) -> Option<&'a str> {
+    if old_status_details == new_status_details {
+        return None;
+    }
+    new_status_details
+        .as_ref()
+        .and_then(|details| details.as_str())
+        .filter(|status| TRANSITION_STATUSES.contains(status))
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct RemoveStove;
+
+impl Handler<RemoveStove> for StoveActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: RemoveStove, ctx: &mut Self::Context) -> Self::Result {
+        for entities in self.published_entities.drain(..) {
+            info!("Removing entities of {entities}");
+            self.mqtt_addr.do_send(RemoveDevice {
+                topic_prefix: entities.topic_prefix.clone(),
+                entities: entities.list_entities(),
+            });
+        }
+        self.health.remove_task(&self.status_task_name());
+        self.status_task.remove();
+        ctx.stop();
+    }
+}
+
+impl Handler<Reconfigure> for StoveActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reconfigure, _ctx: &mut Self::Context) -> Self::Result {
+        let Reconfigure(config) = msg;
+        let old_policies = (
+            &self.config.stove_status_repeat_schedule,
+            self.config.stove_status_backoff_ceil,
+            self.config.stove_status_fast_repeat_interval,
+            self.config.stove_status_fast_repeat_window,
+            self.config.backoff_jitter,
+            self.config.timezone,
+        );
+        let new_policies = (
+            &config.stove_status_repeat_schedule,
+            config.stove_status_backoff_ceil,
+            config.stove_status_fast_repeat_interval,
+            config.stove_status_fast_repeat_window,
+            config.backoff_jitter,
+            config.timezone,
+        );
+        if old_policies != new_policies {
+            let stove_id = &self.last_status.stove_id;
+            let (repeat_policy, backoff_policy) = self.status_policies(&config);
+            info!("Rescheduling stove id {stove_id} data update using policy {repeat_policy} and {backoff_policy}");
+            if let Some(policies) = &self.status_policies {
+                let _ = policies.unbounded_send((repeat_policy, backoff_policy));
+            }
+        }
+        self.config = StoveDiscoveryActorConfiguration {
+            account: self.config.account.clone(),
+            topics: self.config.topics.clone(),
+            ..config
+        };
+    }
+}
+
+impl Handler<Stop> for StoveActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Stop, ctx: &mut Self::Context) -> Self::Result {
+        self.health.remove_task(&self.status_task_name());
+        ctx.stop();
+    }
+}
+
+/// Executes the commands still waiting for the end of their grace period, they are reported as
+/// failed when they can't be executed.
+impl Handler<Shutdown> for StoveActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        let commands: Vec<StoveCommand> = self.pending_commands.drain(..).collect();
+        let client = self.rika_firenet_client.clone();
+        let circuit_breaker = self.circuit_breaker.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let stove_id = self.last_status.stove_id.clone();
+        Box::pin(async move {
+            if commands.is_empty() {
+                return;
+            }
+            let count = commands.len();
+            let result = execute_commands(
+                client,
+                circuit_breaker,
+                rate_limiter,
+                stove_id.clone(),
+                commands,
+            )
+            .await;
+            if let Err(error) = result {
+                error!("{count} pending commands failed for stove id={stove_id}: {error}");
             }
+        })
+    }
+}
+
+/// Applies the commands to the current stove controls, then fetches the resulting status.
+///
+/// Commands are never delayed by the requests budget, the next status updates are delayed instead.
+/// They fail right away while the circuit breaker is open.
+async fn execute_commands(
+    client: RikaFirenetClient,
+    circuit_breaker: CircuitBreaker,
+    rate_limiter: RateLimiter,
+    stove_id: String,
+    commands: Vec<StoveCommand>,
+) -> Result<StoveStatus> {
+    info!(
+        "Executing commands for stove id={stove_id}:\n{}",
+        commands
+            .iter()
+            .map(|c| format!("- {:?}", c))
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+    if let Err(delay) = circuit_breaker.try_acquire() {
+        bail!(
+            "API calls are paused by the circuit breaker for {}",
+            delay.prettify()
+        );
+    }
+    // reading the controls, writing them and reading the resulting status
+    for _ in 0..3 {
+        rate_limiter.consume();
+    }
+    let result = async {
+        let mut controls = *metrics::observe_api_call("rika", "status", client.status(&stove_id))
+            .await?
+            .controls;
+        for command in commands {
+            command.apply_to(&mut controls);
         }
+        metrics::observe_api_call(
+            "rika",
+            "restore_controls",
+            client.restore_controls(&stove_id, controls),
+        )
+        .await?;
+        Ok::<_, anyhow::Error>(
+            metrics::observe_api_call("rika", "status", client.status(&stove_id)).await?,
+        )
     }
+    .await;
+    circuit_breaker.record(result.is_ok());
+    result
 }
 
 impl Handler<StoveCommand> for StoveActor {
     type Result = ();
 
     fn handle(&mut self, cmd: StoveCommand, ctx: &mut Self::Context) -> Self::Result {
+        metrics::record_stove_command(cmd.name());
         self.pending_commands.push(cmd);
         let grace_period = DEDUPLICATE_COMMANDS_GRACE_TIME
             .to_std()
@@ -300,31 +864,26 @@ impl Handler<StoveCommand> for StoveActor {
         let pending_commands_before_grace_period = self.pending_commands.clone();
         ctx.run_later(grace_period, move |act, ctx| {
             let client = act.rika_firenet_client.clone();
+            let circuit_breaker = act.circuit_breaker.clone();
+            let rate_limiter = act.rate_limiter.clone();
             if pending_commands_before_grace_period == act.pending_commands {
+                act.pending_commands.clear();
                 let stove_id = act.last_status.stove_id.clone();
-                info!(
-                    "Executing commands for stove id={stove_id}:\n{}",
-                    pending_commands_before_grace_period
-                        .iter()
-                        .map(|c| format!("- {:?}", c))
-                        .collect::<Vec<String>>()
-                        .join("\n")
-                );
-                async move {
-                    let mut controls = *client.status(&stove_id).await?.controls;
-                    for command in pending_commands_before_grace_period {
-                        command.apply_to(&mut controls);
-                    }
-                    client.restore_controls(&stove_id, controls).await?;
-                    client.status(&stove_id).await
-                }
+                execute_commands(
+                    client,
+                    circuit_breaker,
+                    rate_limiter,
+                    stove_id,
+                    pending_commands_before_grace_period,
+                )
                 .into_actor(act)
-                .map(move |res, _act, ctx| {
+                .map(move |res, act, ctx| {
                     match res {
                         Ok(status) => {
                             ctx.add_stream(stream! {
                                 yield status;
                             });
+                            act.poll_faster("commands");
                         }
                         Err(err) => {
                             error!("Stove controls update failed: {err}");
@@ -348,8 +907,8 @@ struct StoveMetadata {
     topic_prefix: String,
 }
 
-impl From<&StoveStatus> for StoveMetadata {
-    fn from(value: &StoveStatus) -> Self {
+impl StoveMetadata {
+    fn new(value: &StoveStatus, base_topic: &str) -> Self {
         let manufacturer = value.oem.clone();
         let model = value.stove_type.clone();
         let name = value.name.clone();
@@ -360,7 +919,7 @@ impl From<&StoveStatus> for StoveMetadata {
         let version = value.sensors.parameter_version_main_board.to_string();
         let (version_major, version_minor) = version.split_at(1);
 
-        let topic_prefix = format!("{COMMON_BASE_TOPIC}/{unique_id}");
+        let topic_prefix = format!("{base_topic}/{unique_id}");
         StoveMetadata {
             manufacturer,
             model,
@@ -437,7 +996,7 @@ impl HaMqttEntity<StoveStatus> for RikaEntities {
         return entities;
     }
 
-    fn build_payloads(&self, data: StoveStatus) -> Vec<PublishEntityData> {
+    fn build_payloads(&self, data: &StoveStatus) -> Vec<PublishEntityData> {
         let topic_prefix = &self.topic_prefix;
         vec![
             PublishEntityData::new(
@@ -449,8 +1008,16 @@ impl HaMqttEntity<StoveStatus> for RikaEntities {
     }
 }
 
-impl From<&StoveStatus> for RikaEntities {
-    fn from(stove_status: &StoveStatus) -> RikaEntities {
+impl RikaEntities {
+    /// Payloads of a status, flagged as saved by a previous run or as fresh.
+    fn status_payloads(&self, stove_status: &StoveStatus, stale: bool) -> Vec<PublishEntityData> {
+        self.build_payloads(stove_status)
+            .into_iter()
+            .map(|payload| payload.stale(stale))
+            .collect()
+    }
+
+    fn new(stove_status: &StoveStatus, config: &StoveDiscoveryActorConfiguration) -> RikaEntities {
         let StoveMetadata {
             manufacturer,
             model,
@@ -460,7 +1027,7 @@ impl From<&StoveStatus> for RikaEntities {
             object_id,
             version,
             topic_prefix,
-        } = &stove_status.into();
+        } = &StoveMetadata::new(stove_status, &config.base_topic());
 
         let origin = app_infos::origin();
 
@@ -472,16 +1039,19 @@ impl From<&StoveStatus> for RikaEntities {
             .model(model)
             .sw_version(version);
 
-        let availability = Availability::single(
+        let availability = Availability::all(vec![
+            config.topics.bridge_availability(),
             AvailabilityCheck::topic("~/state")
                 .payload_available("0")
                 .value_template("{{ value_json.lastSeenMinutes }}"),
-        )
+        ])
         .expire_after(RIKA_SENSOR_EXPIRATION_TIME.num_seconds().unsigned_abs());
 
         let sensor_defaults = Sensor::default()
             .topic_prefix(topic_prefix)
             .state_topic("~/state")
+            .json_attributes_topic("~/state")
+            .json_attributes_template(STALE_ATTRIBUTES_TEMPLATE)
             .origin(origin.clone())
             .device(device.clone())
             .availability(availability.clone());
@@ -860,6 +1430,19 @@ enum StoveCommand {
 }
 
 impl StoveCommand {
+    fn name(&self) -> &'static str {
+        match self {
+            StoveCommand::OnOff(_) => "on_off",
+            StoveCommand::OperatingMode(_) => "operating_mode",
+            StoveCommand::TargetTemperature(_) => "target_temperature",
+            StoveCommand::IdleTemperature(_) => "idle_temperature",
+            StoveCommand::PowerHeating(_) => "power_heating",
+            StoveCommand::DailySchedulesEnabled(_) => "daily_schedules_enabled",
+            StoveCommand::FrostProtectionEnabled(_) => "frost_protection_enabled",
+            StoveCommand::FrostProtectionTemperature(_) => "frost_protection_temperature",
+        }
+    }
+
     fn apply_to(self, controls: &mut StoveControls) {
         match self {
             StoveCommand::OnOff(enabled) => controls.on_off = Some(enabled),
@@ -890,11 +1473,10 @@ struct RikaFirenetCommand {
     command: StoveCommand,
 }
 
-impl TryFrom<MqttMessage> for RikaFirenetCommand {
-    type Error = anyhow::Error;
-
-    fn try_from(msg: MqttMessage) -> Result<Self, Self::Error> {
-        let command_topic_re = Regex::new(&format!("^({COMMON_BASE_TOPIC}/[^/]+)/([^/]+)/set$"))
+impl RikaFirenetCommand {
+    fn parse(msg: MqttMessage, base_topic: &str) -> Result<Self> {
+        let base_topic = regex::escape(base_topic);
+        let command_topic_re = Regex::new(&format!("^({base_topic}/[^/]+)/([^/]+)/set$"))
             .expect("A valid regular expression for rika stove command topic");
         match command_topic_re.captures(&msg.topic).map(|c| c.extract()) {
             Some((_, [topic_prefix, attribute])) => {
@@ -921,3 +1503,94 @@ impl TryFrom<MqttMessage> for RikaFirenetCommand {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rika_firenet_client::StoveStatus;
+    use serde_json::{json, Value};
+
+    use crate::{
+        mqtt::{PublishEntityData, Topics},
+        repeat::policy::{Jitter, Schedule},
+    };
+
+    use super::{entered_transition, RikaEntities, StoveDiscoveryActorConfiguration};
+
+    fn config(account: Option<&str>, namespace: Option<&str>) -> StoveDiscoveryActorConfiguration {
+        StoveDiscoveryActorConfiguration {
+            account: account.map(str::to_string),
+            stove_discovery_repeat_interval: Duration::from_secs(3600)..=Duration::from_secs(3600),
+            stove_discovery_backoff_ceil: Duration::from_secs(3600),
+            stove_status_repeat_schedule: Schedule::between(
+                Duration::from_secs(600)..=Duration::from_secs(600),
+            ),
+            stove_status_backoff_ceil: Duration::from_secs(3600),
+            stove_status_fast_repeat_interval: Duration::from_secs(30),
+            stove_status_fast_repeat_window: Duration::from_secs(300),
+            backoff_jitter: Jitter::None,
+            timezone: None,
+            missing_device_removal_threshold: 3,
+            requests_per_hour: 0,
+            topics: Topics {
+                namespace: namespace.map(str::to_string),
+                ..Topics::default()
+            },
+        }
+    }
+
+    #[test]
+    fn namespaces_topics_and_tasks_of_named_accounts() {
+        let unnamed = config(None, None);
+        assert_eq!(unnamed.base_topic(), "rika-firenet");
+        assert_eq!(unnamed.task_name("discovery"), "rika/discovery");
+
+        let named = config(Some("Chalet Zoë"), Some("home"));
+        assert_eq!(named.base_topic(), "home/rika-firenet/Chalet_Zoe");
+        assert_eq!(
+            named.task_name("stove/12345/status"),
+            "rika/Chalet Zoë/stove/12345/status"
+        );
+    }
+
+    #[test]
+    fn detects_transition_statuses() {
+        let standby = Some(json!("Standby"));
+        let ignition = Some(json!("Ignition"));
+        let burnout = Some(json!("Burnout"));
+        assert_eq!(entered_transition(&standby, &ignition), Some("Ignition"));
+        assert_eq!(entered_transition(&ignition, &burnout), Some("Burnout"));
+        assert_eq!(entered_transition(&None, &ignition), Some("Ignition"));
+        assert_eq!(
+            entered_transition(&ignition, &ignition),
+            None,
+            "status did not change"
+        );
+        assert_eq!(entered_transition(&burnout, &standby), None);
+        assert_eq!(entered_transition(&standby, &None), None);
+    }
+
+    #[test]
+    fn flags_payloads_of_saved_statuses_as_stale() {
+        let stove_status = StoveStatus {
+            stove_id: "12345".to_string(),
+            name: "Living room".to_string(),
+            oem: "RIKA".to_string(),
+            stove_type: "DOMO".to_string(),
+            ..StoveStatus::default()
+        };
+        let entities = RikaEntities::new(&stove_status, &config(None, None));
+        let state_topic = "rika-firenet/RIKA_DOMO_Living_room-12345/state".to_string();
+        let state_payload = |stale: bool| {
+            let mut payload = serde_json::to_value(&stove_status).unwrap();
+            payload["stale"] = Value::Bool(stale);
+            PublishEntityData::new(state_topic.clone(), payload)
+        };
+
+        let payloads = entities.status_payloads(&stove_status, true);
+        assert!(payloads.contains(&state_payload(true)), "{payloads:?}");
+        let payloads = entities.status_payloads(&stove_status, false);
+        assert!(payloads.contains(&state_payload(false)), "{payloads:?}");
+    }
+}
@@ -0,0 +1,26 @@
+use actix::Message;
+use actix_web::rt::signal::{
+    self,
+    unix::{signal, SignalKind},
+};
+use anyhow::Result;
+use futures::future::select;
+use std::pin::pin;
+
+/// Asks an actor to complete its pending work before the system stops, the response is sent once
+/// done.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Shutdown;
+
+/// Stops an actor and the actors it started, their Home Assistant entities are left untouched.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Stop;
+
+/// Waits for SIGINT or SIGTERM.
+pub async fn termination_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    select(pin!(signal::ctrl_c()), pin!(terminate.recv())).await;
+    Ok(())
+}
@@ -0,0 +1,663 @@
+use actix::Addr;
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use ha_mqtt_discovery::{
+    mqtt::{common::EntityCategory, device_classes::SensorDeviceClass, sensor::Sensor},
+    Entity,
+};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use reqwest::StatusCode;
+use reqwest_middleware::ClientWithMiddleware;
+use ring::{
+    aead, digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use somfy_protect_client::models::{DeviceOutput, SiteOutput};
+use somfy_protect_openapi::apis::{self, configuration::Configuration, device_api, site_api};
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    metrics,
+    misc::{app_infos, hostname, Sluggable},
+    mqtt::{EntityConfiguration, MqttActor, PublishEntityData, Topics},
+    repeat::{HttpStatusError, RetryHint},
+    state::PersistedTask,
+};
+
+const DEFAULT_API_BASE_URL: &str = "https://api.myfox.io";
+const DEFAULT_AUTH_BASE_URL: &str = "https://sso.myfox.io";
+lazy_static! {
+    /// Access tokens are refreshed this long before they expire, not to expire during API calls
+    static ref TOKEN_REFRESH_MARGIN: TimeDelta = TimeDelta::minutes(1);
+    /// Access tokens are renewed this long before they expire by [SomfyApiClient::renew_token]
+    static ref TOKEN_RENEWAL_AHEAD: TimeDelta = TimeDelta::minutes(5);
+}
+
+/// Somfy Protect API client managing its own OAuth tokens for the requests of the generated API
+/// client: the access token is refreshed before it expires and the refresh token can be saved,
+/// encrypted, to skip the password login on the next start.
+#[derive(Clone)]
+pub struct SomfyApiClient {
+    http: ClientWithMiddleware,
+    api_base_url: String,
+    auth_base_url: String,
+    credentials: Arc<Credentials>,
+    tokens: Arc<Mutex<Option<Tokens>>>,
+    /// Held while tokens are requested, for concurrent calls not to log in twice
+    token_request: Arc<futures::lock::Mutex<()>>,
+    token_cache: Option<TokenCache>,
+}
+
+#[derive(Default)]
+struct Credentials {
+    client_id: String,
+    client_secret: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Clone)]
+struct Tokens {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: DateTime<Utc>,
+}
+
+impl Tokens {
+    fn expire_within(&self, margin: TimeDelta, now: DateTime<Utc>) -> bool {
+        self.expires_at - margin <= now
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: i64,
+}
+
+impl SomfyApiClient {
+    pub async fn list_sites(&self) -> Result<Vec<SiteOutput>> {
+        let configuration = self.configuration().await?;
+        let sites =
+            metrics::observe_api_call("somfy", "list_sites", site_api::list_sites(&configuration))
+                .await
+                .map_err(api_error)?;
+        Ok(sites.items)
+    }
+
+    pub async fn list_devices(&self, site_id: String) -> Result<Vec<DeviceOutput>> {
+        let configuration = self.configuration().await?;
+        let devices = metrics::observe_api_call(
+            "somfy",
+            "list_devices",
+            device_api::list_devices(&configuration, &site_id),
+        )
+        .await
+        .map_err(api_error)?;
+        Ok(devices.items)
+    }
+
+    /// Expiry of the current access token, [None] before the first login.
+    pub fn token_expiry(&self) -> Option<DateTime<Utc>> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens.as_ref().map(|tokens| tokens.expires_at)
+    }
+
+    /// When [SomfyApiClient::renew_token] renews the current access token, [None] before the
+    /// first login.
+    pub fn token_renewal_time(&self) -> Option<DateTime<Utc>> {
+        self.token_expiry()
+            .map(|expires_at| expires_at - *TOKEN_RENEWAL_AHEAD)
+    }
+
+    /// Renews the access token ahead of its expiry, for the API calls not to wait for a new one.
+    pub async fn renew_token(&self) -> Result<()> {
+        self.access_token(*TOKEN_RENEWAL_AHEAD).await.map(|_| ())
+    }
+
+    /// Configuration of the generated API client, authenticated by the current access token.
+    async fn configuration(&self) -> Result<Configuration> {
+        Ok(Configuration {
+            base_path: self.api_base_url.clone(),
+            client: self.http.clone(),
+            oauth_access_token: Some(self.access_token(*TOKEN_REFRESH_MARGIN).await?),
+            ..Configuration::default()
+        })
+    }
+
+    /// Returns the current access token, or a new one when it expires within `margin`. Tokens
+    /// are refreshed with the refresh token, saved by a previous run on startup, and the password
+    /// login is the fallback when the refresh fails.
+    async fn access_token(&self, margin: TimeDelta) -> Result<String> {
+        let _token_request = self.token_request.lock().await;
+        let known_tokens = self.tokens.lock().unwrap().clone();
+        if let Some(tokens) = &known_tokens {
+            if !tokens.expire_within(margin, Utc::now()) {
+                return Ok(tokens.access_token.clone());
+            }
+        }
+        let refresh_token = match known_tokens {
+            Some(tokens) => tokens.refresh_token,
+            None => self.token_cache.as_ref().and_then(TokenCache::load),
+        };
+        let tokens = match refresh_token {
+            Some(refresh_token) => match self.refresh(&refresh_token).await {
+                Ok(tokens) => Tokens {
+                    refresh_token: tokens.refresh_token.or(Some(refresh_token)),
+                    ..tokens
+                },
+                Err(error) => {
+                    warn!("Unable to refresh Somfy Protect token, logging in again: {error:#}");
+                    self.login().await?
+                }
+            },
+            None => self.login().await?,
+        };
+        if let (Some(token_cache), Some(refresh_token)) = (&self.token_cache, &tokens.refresh_token)
+        {
+            token_cache.save(refresh_token);
+        }
+        let access_token = tokens.access_token.clone();
+        *self.tokens.lock().unwrap() = Some(tokens);
+        Ok(access_token)
+    }
+
+    async fn login(&self) -> Result<Tokens> {
+        info!(
+            "Logging in to Somfy Protect as {}",
+            self.credentials.username
+        );
+        let grant = [
+            ("grant_type", "password"),
+            ("username", &self.credentials.username),
+            ("password", &self.credentials.password),
+        ];
+        self.request_tokens("login", &grant)
+            .await
+            .map_err(|error| match error.downcast::<HttpStatusError>() {
+                // OAuth servers reject invalid credentials with a 400 Bad Request
+                Ok(error) if error.status == StatusCode::BAD_REQUEST => anyhow!(HttpStatusError {
+                    retry_hint: RetryHint::Never,
+                    ..error
+                }),
+                Ok(error) => anyhow!(error),
+                Err(error) => error,
+            })
+            .context("Somfy Protect login failed")
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<Tokens> {
+        let grant = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ];
+        self.request_tokens("refresh_token", &grant).await
+    }
+
+    async fn request_tokens(&self, operation: &str, grant: &[(&str, &str)]) -> Result<Tokens> {
+        let mut form = vec![
+            ("client_id", self.credentials.client_id.as_str()),
+            ("client_secret", self.credentials.client_secret.as_str()),
+        ];
+        form.extend_from_slice(grant);
+        let request = self
+            .http
+            .post(format!("{}/oauth/oauth/v2/token", self.auth_base_url))
+            .form(&form)
+            .send();
+        let response = metrics::observe_api_call("somfy", operation, request).await?;
+        let body = HttpStatusError::check(response)?.bytes().await?;
+        let token: TokenResponse =
+            serde_json::from_slice(&body).context("Invalid Somfy Protect token response")?;
+        Ok(Tokens {
+            access_token: token.access_token,
+            refresh_token: token.refresh_token,
+            expires_at: Utc::now() + TimeDelta::seconds(token.expires_in),
+        })
+    }
+}
+
+/// Keeps the status of error responses, their retry hint depends on it.
+fn api_error<E: Debug + Send + Sync + 'static>(error: apis::Error<E>) -> anyhow::Error {
+    match error {
+        apis::Error::ResponseError(response) => anyhow!(HttpStatusError {
+            status: response.status,
+            retry_hint: RetryHint::from_http_status(response.status.as_u16()),
+        }),
+        error => error.into(),
+    }
+}
+
+#[derive(Default)]
+pub struct SomfyApiClientBuilder {
+    api_base_url: Option<String>,
+    auth_base_url: Option<String>,
+    credentials: Credentials,
+    token_cache: Option<TokenCache>,
+}
+
+impl SomfyApiClientBuilder {
+    pub fn with_client_credentials(mut self, client_id: String, client_secret: String) -> Self {
+        self.credentials.client_id = client_id;
+        self.credentials.client_secret = client_secret;
+        self
+    }
+
+    pub fn with_user_credentials(mut self, username: String, password: String) -> Self {
+        self.credentials.username = username;
+        self.credentials.password = password;
+        self
+    }
+
+    pub fn with_api_base_url<S: Into<String>>(mut self, api_base_url: S) -> Self {
+        self.api_base_url = Some(api_base_url.into());
+        self
+    }
+
+    pub fn with_auth_base_url<S: Into<String>>(mut self, auth_base_url: S) -> Self {
+        self.auth_base_url = Some(auth_base_url.into());
+        self
+    }
+
+    /// Saves the refresh token in the task data, encrypted with the given key.
+    pub fn with_token_cache(mut self, task: PersistedTask, key: &str) -> Self {
+        self.token_cache = Some(TokenCache {
+            task,
+            cipher: TokenCipher::new(key),
+        });
+        self
+    }
+
+    pub fn build(self) -> SomfyApiClient {
+        SomfyApiClient {
+            http: ClientWithMiddleware::from(reqwest::Client::new()),
+            api_base_url: self
+                .api_base_url
+                .unwrap_or(DEFAULT_API_BASE_URL.to_string()),
+            auth_base_url: self
+                .auth_base_url
+                .unwrap_or(DEFAULT_AUTH_BASE_URL.to_string()),
+            credentials: Arc::new(self.credentials),
+            tokens: Arc::new(Mutex::new(None)),
+            token_request: Arc::new(futures::lock::Mutex::new(())),
+            token_cache: self.token_cache,
+        }
+    }
+}
+
+/// Refresh token saved in the state of a task, encrypted.
+#[derive(Clone)]
+struct TokenCache {
+    task: PersistedTask,
+    cipher: TokenCipher,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedToken {
+    encrypted_refresh_token: String,
+}
+
+impl TokenCache {
+    fn load(&self) -> Option<String> {
+        let saved_token: SavedToken = self.task.data()?;
+        self.cipher
+            .decrypt(&saved_token.encrypted_refresh_token)
+            .inspect_err(|error| warn!("Ignoring saved Somfy Protect token: {error:#}"))
+            .ok()
+    }
+
+    fn save(&self, refresh_token: &str) {
+        match self.cipher.encrypt(refresh_token) {
+            Ok(encrypted_refresh_token) => self.task.save_data(&SavedToken {
+                encrypted_refresh_token,
+            }),
+            Err(error) => warn!("Unable to save Somfy Protect token: {error:#}"),
+        }
+    }
+}
+
+/// ChaCha20-Poly1305 encryption keyed by the SHA-256 digest of the configured key, the random
+/// nonce is prepended to the encrypted token.
+#[derive(Clone)]
+struct TokenCipher(Arc<aead::LessSafeKey>);
+
+impl TokenCipher {
+    fn new(key: &str) -> Self {
+        let key = digest::digest(&digest::SHA256, key.as_bytes());
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key.as_ref())
+            .expect("A SHA-256 digest to be a valid ChaCha20 key");
+        Self(Arc::new(aead::LessSafeKey::new(key)))
+    }
+
+    fn encrypt(&self, token: &str) -> Result<String> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Can't generate a nonce"))?;
+        let mut encrypted_token = token.as_bytes().to_vec();
+        self.0
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut encrypted_token,
+            )
+            .map_err(|_| anyhow!("Can't encrypt token"))?;
+        Ok(BASE64.encode([nonce.as_slice(), &encrypted_token].concat()))
+    }
+
+    fn decrypt(&self, encrypted_token: &str) -> Result<String> {
+        let encrypted_token = BASE64.decode(encrypted_token)?;
+        if encrypted_token.len() < aead::NONCE_LEN {
+            return Err(anyhow!("Truncated token"));
+        }
+        let (nonce, encrypted_token) = encrypted_token.split_at(aead::NONCE_LEN);
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Invalid nonce"))?;
+        let mut token = encrypted_token.to_vec();
+        let token = self
+            .0
+            .open_in_place(nonce, aead::Aad::empty(), &mut token)
+            .map_err(|_| anyhow!("Can't decrypt token, was the key changed?"))?;
+        Ok(String::from_utf8(token.to_vec())?)
+    }
+}
+
+/// Home Assistant diagnostic timestamp sensor of the bridge device, reporting when the access
+/// token of an account expires.
+pub struct TokenExpirySensor {
+    mqtt_addr: Addr<MqttActor>,
+    expiry: TokenExpiry,
+}
+
+impl TokenExpirySensor {
+    /// Publishes the sensor, initially unknown.
+    pub fn new(name: String, topics: Topics, mqtt_addr: Addr<MqttActor>) -> Self {
+        let expiry = TokenExpiry {
+            name,
+            topics,
+            expires_at: None,
+        };
+        mqtt_addr.do_send(EntityConfiguration(expiry.entity()));
+        mqtt_addr.do_send(expiry.state());
+        Self { mqtt_addr, expiry }
+    }
+
+    /// Publishes the expiry when it changed.
+    pub fn update(&mut self, expires_at: Option<DateTime<Utc>>) {
+        if self.expiry.update(expires_at) {
+            self.mqtt_addr.do_send(self.expiry.state());
+        }
+    }
+}
+
+/// Entity and state of a [TokenExpirySensor].
+struct TokenExpiry {
+    name: String,
+    topics: Topics,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl TokenExpiry {
+    /// Records the expiry, returns whether it changed.
+    fn update(&mut self, expires_at: Option<DateTime<Utc>>) -> bool {
+        let changed = self.expires_at != expires_at;
+        self.expires_at = expires_at;
+        changed
+    }
+
+    fn state_topic(&self) -> String {
+        self.topics
+            .bridge(&format!("token-expiry/{}", self.name.slug()))
+    }
+
+    fn entity(&self) -> Entity {
+        let unique_id = format!(
+            "{}-{}-{}-token-expiry",
+            app_infos::name(),
+            hostname(),
+            self.name
+        )
+        .slug();
+        Entity::Sensor(
+            Sensor::default()
+                .name(format!("{} token expiry", self.name))
+                .unique_id(&unique_id)
+                .object_id(&unique_id)
+                .state_topic(self.state_topic())
+                .value_template("{{ value_json.expires_at }}")
+                .device_class(SensorDeviceClass::Timestamp)
+                .entity_category(EntityCategory::Diagnostic)
+                .origin(app_infos::origin())
+                .device(app_infos::device())
+                .availability(vec![self.topics.bridge_availability()]),
+        )
+    }
+
+    fn state(&self) -> PublishEntityData {
+        PublishEntityData::new(
+            self.state_topic(),
+            json!({ "expires_at": self.expires_at.map(|expires_at| expires_at.to_rfc3339()) }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use actix_web::{dev::ServerHandle, web, App, HttpResponse, HttpServer};
+    use chrono::{TimeDelta, TimeZone, Utc};
+    use serde_json::json;
+
+    use crate::{
+        mqtt::{PublishEntityData, Topics},
+        state::StateStore,
+    };
+
+    use super::{
+        SomfyApiClient, SomfyApiClientBuilder, TokenCache, TokenCipher, TokenExpiry, Tokens,
+        TOKEN_REFRESH_MARGIN,
+    };
+
+    /// Grants received by the token endpoint, e.g. `password:user`.
+    type Grants = Arc<Mutex<Vec<String>>>;
+
+    /// Token endpoint rejecting the `revoked` refresh token, as OAuth servers do with a 400 Bad
+    /// Request.
+    async fn token_endpoint(
+        form: web::Form<HashMap<String, String>>,
+        grants: web::Data<Grants>,
+    ) -> HttpResponse {
+        let grant = match form["grant_type"].as_str() {
+            "password" => format!("password:{}", form["username"]),
+            grant_type => format!("{grant_type}:{}", form["refresh_token"]),
+        };
+        let mut grants = grants.lock().unwrap();
+        grants.push(grant);
+        if grants.last().unwrap() == "refresh_token:revoked" {
+            return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+        }
+        HttpResponse::Ok().json(json!({
+            "access_token": format!("access token {}", grants.len()),
+            "refresh_token": format!("refresh token {}", grants.len()),
+            "expires_in": 3600,
+        }))
+    }
+
+    async fn start_token_server(grants: Grants) -> (String, ServerHandle) {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(grants.clone()))
+                .route("/oauth/oauth/v2/token", web::post().to(token_endpoint))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        (url, handle)
+    }
+
+    fn token_cache(state: &StateStore) -> TokenCache {
+        TokenCache {
+            task: state.task("somfy/token"),
+            cipher: TokenCipher::new("secret key"),
+        }
+    }
+
+    fn client(auth_base_url: &str, state: &StateStore) -> SomfyApiClient {
+        SomfyApiClientBuilder::default()
+            .with_client_credentials("client id".to_string(), "client secret".to_string())
+            .with_user_credentials("user".to_string(), "password".to_string())
+            .with_auth_base_url(auth_base_url)
+            .with_token_cache(state.task("somfy/token"), "secret key")
+            .build()
+    }
+
+    #[test]
+    fn can_decrypt_encrypted_tokens() {
+        let cipher = TokenCipher::new("secret key");
+
+        let encrypted_token = cipher.encrypt("refresh token").unwrap();
+
+        assert!(!encrypted_token.contains("refresh token"));
+        assert_ne!(cipher.encrypt("refresh token").unwrap(), encrypted_token);
+        assert_eq!(cipher.decrypt(&encrypted_token).unwrap(), "refresh token");
+    }
+
+    #[test]
+    fn can_not_decrypt_tokens_with_another_key() {
+        let encrypted_token = TokenCipher::new("secret key")
+            .encrypt("refresh token")
+            .unwrap();
+
+        let cipher = TokenCipher::new("other key");
+        assert!(cipher.decrypt(&encrypted_token).is_err());
+        assert!(cipher.decrypt("").is_err());
+        assert!(cipher.decrypt("not base64").is_err());
+    }
+
+    #[test]
+    fn refreshes_tokens_before_they_expire() {
+        let now = Utc::now();
+        let margin = *TOKEN_REFRESH_MARGIN;
+        let tokens = |expires_in| Tokens {
+            access_token: "access token".to_string(),
+            refresh_token: None,
+            expires_at: now + expires_in,
+        };
+
+        assert!(!tokens(TimeDelta::minutes(5)).expire_within(margin, now));
+        assert!(tokens(TimeDelta::seconds(30)).expire_within(margin, now));
+        assert!(tokens(TimeDelta::minutes(-5)).expire_within(margin, now));
+    }
+
+    #[actix_web::test]
+    async fn refreshes_the_saved_token_on_startup() {
+        let grants = Grants::default();
+        let (url, server) = start_token_server(grants.clone()).await;
+        let state = StateStore::load(None, Duration::ZERO);
+        token_cache(&state).save("saved token");
+
+        let client = client(&url, &state);
+        let access_token = client.access_token(*TOKEN_REFRESH_MARGIN).await.unwrap();
+
+        assert_eq!(access_token, "access token 1");
+        assert_eq!(*grants.lock().unwrap(), vec!["refresh_token:saved token"]);
+        assert_eq!(
+            token_cache(&state).load().as_deref(),
+            Some("refresh token 1")
+        );
+        assert!(client.token_expiry().is_some());
+        server.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn logs_in_when_the_saved_token_is_revoked() {
+        let grants = Grants::default();
+        let (url, server) = start_token_server(grants.clone()).await;
+        let state = StateStore::load(None, Duration::ZERO);
+        token_cache(&state).save("revoked");
+
+        let client = client(&url, &state);
+        let access_token = client.access_token(*TOKEN_REFRESH_MARGIN).await.unwrap();
+
+        assert_eq!(access_token, "access token 2");
+        assert_eq!(
+            *grants.lock().unwrap(),
+            vec!["refresh_token:revoked", "password:user"]
+        );
+        assert_eq!(
+            token_cache(&state).load().as_deref(),
+            Some("refresh token 2")
+        );
+        server.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn renews_tokens_ahead_of_their_expiry() {
+        let grants = Grants::default();
+        let (url, server) = start_token_server(grants.clone()).await;
+        let state = StateStore::load(None, Duration::ZERO);
+
+        let client = client(&url, &state);
+        client.renew_token().await.unwrap();
+        client.renew_token().await.unwrap();
+        assert_eq!(*grants.lock().unwrap(), vec!["password:user"]);
+
+        let renewal_time = client.token_renewal_time().unwrap();
+        assert!(renewal_time < client.token_expiry().unwrap());
+        client.tokens.lock().unwrap().as_mut().unwrap().expires_at = Utc::now();
+        client.renew_token().await.unwrap();
+        assert_eq!(
+            *grants.lock().unwrap(),
+            vec!["password:user", "refresh_token:refresh token 1"]
+        );
+        assert!(client.token_renewal_time().unwrap() > renewal_time);
+        server.stop(false).await;
+    }
+
+    #[test]
+    fn publishes_the_token_expiry_when_it_changes() {
+        let mut expiry = TokenExpiry {
+            name: "Somfy Protect account home".to_string(),
+            topics: Topics::default(),
+            expires_at: None,
+        };
+        let state_topic = expiry.state_topic();
+        assert!(
+            state_topic.ends_with("/token-expiry/Somfy_Protect_account_home"),
+            "{state_topic}"
+        );
+        assert_eq!(
+            expiry.state(),
+            PublishEntityData::new(state_topic.clone(), json!({ "expires_at": null }))
+        );
+
+        let expires_at = Utc.with_ymd_and_hms(2026, 10, 16, 12, 30, 0).unwrap();
+        assert!(expiry.update(Some(expires_at)));
+        assert!(!expiry.update(Some(expires_at)), "expiry did not change");
+        assert_eq!(
+            expiry.state(),
+            PublishEntityData::new(
+                state_topic,
+                json!({ "expires_at": "2026-10-16T12:30:00+00:00" })
+            )
+        );
+        assert!(expiry.update(None));
+    }
+}
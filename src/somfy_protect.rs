@@ -1,10 +1,20 @@
 use crate::{
-    misc::{app_infos, Sluggable},
-    mqtt::{EntityConfiguration, MqttActor, PublishEntityData},
+    circuit_breaker::CircuitBreaker,
+    health::{HealthRegistry, TaskMonitor},
+    misc::{app_infos, HumanReadable, Sluggable},
+    mqtt::{
+        EntityConfiguration, MqttActor, PublishEntityData, RemoveDevice, Topics,
+        STALE_ATTRIBUTES_TEMPLATE,
+    },
+    problem::ProblemSensor,
+    repeat::{RetryHint, Retryable},
+    shutdown::Stop,
+    somfy_api::{SomfyApiClient, TokenExpirySensor},
+    state::{PersistedTask, StateStore},
 };
 use actix::prelude::*;
 use async_stream::stream;
-use chrono::TimeDelta;
+use chrono::{DateTime, TimeDelta, Utc};
 use ha_mqtt_discovery::{
     mqtt::{
         binary_sensor::BinarySensor,
@@ -19,68 +29,289 @@ use ha_mqtt_discovery::{
     Entity,
 };
 use lazy_static::lazy_static;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use serde_json::Value;
-use somfy_protect_client::{
-    client::SomfyProtectClient,
-    models::{device_definition::Type, DeviceOutput, SiteOutput},
+use somfy_protect_client::models::{device_definition::Type, DeviceOutput, SiteOutput};
+use std::{
+    cmp,
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
+    ops::Deref,
+    time::Duration,
+    vec,
 };
-use std::{collections::HashMap, fmt::Display, ops::Deref, time::Duration, vec};
 
 const MANUFACTURER: &str = "Somfy";
 const ALT_MANUFACTURER: &str = "Myfox";
 const VALID_MANUFACTURERS: [&str; 2] = [MANUFACTURER, ALT_MANUFACTURER];
+const COMMON_BASE_TOPIC: &str = "somfy-protect";
 lazy_static! {
     static ref SITES_SCRAPE_INTERVAL: TimeDelta = TimeDelta::minutes(5);
     static ref DEVICES_SCRAPE_INTERVAL: TimeDelta = TimeDelta::minutes(1);
     static ref SENSORS_EXPIRATION_TIME: TimeDelta = TimeDelta::minutes(1);
+    /// Spaces out the attempts to renew a token which is still about to expire
+    static ref TOKEN_RENEWAL_MIN_DELAY: TimeDelta = TimeDelta::minutes(1);
 }
 
+#[derive(Clone)]
+pub struct SomfyActorConfiguration {
+    /// Name of the account, [None] for the unnamed account configured by the command line
+    pub account: Option<String>,
+    pub missing_device_removal_threshold: u32,
+    pub topics: Topics,
+}
+
+impl SomfyActorConfiguration {
+    pub fn task_name(&self, task: &str) -> String {
+        match &self.account {
+            Some(account) => format!("somfy/{account}/{task}"),
+            None => format!("somfy/{task}"),
+        }
+    }
+
+    fn display_name(&self) -> String {
+        match &self.account {
+            Some(account) => format!("Somfy Protect account {account}"),
+            None => "Somfy Protect".to_string(),
+        }
+    }
+}
+
+/// Applies a new configuration to a running actor, in place. Changes of the account or of the
+/// topics are ignored.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reconfigure(pub SomfyActorConfiguration);
+
 pub struct SomfyActor {
+    config: SomfyActorConfiguration,
     mqtt_addr: Addr<MqttActor>,
-    somfy_client: SomfyProtectClient,
+    somfy_client: SomfyApiClient,
+    sites_monitor: TaskMonitor,
+    devices_monitor: TaskMonitor,
+    sites_task: PersistedTask,
+    devices_task: PersistedTask,
+    circuit_breaker: CircuitBreaker,
+    problem: ProblemSensor,
+    token_expiry: TokenExpirySensor,
+    /// Renewal of the access token, scheduled at the given time
+    token_renewal: Option<(DateTime<Utc>, SpawnHandle)>,
+    /// Error which stopped the scraping, retrying can't fix it
+    permanent_error: Option<String>,
     sites: HashMap<String, AlarmSite>,
 }
 
 impl SomfyActor {
-    pub fn new(mqtt_addr: Addr<MqttActor>, somfy_client: SomfyProtectClient) -> Self {
+    pub fn new<C: Into<SomfyActorConfiguration>>(
+        configuration: C,
+        mqtt_addr: Addr<MqttActor>,
+        somfy_client: SomfyApiClient,
+        health: HealthRegistry,
+        circuit_breaker: CircuitBreaker,
+        state: StateStore,
+    ) -> Self {
+        let config: SomfyActorConfiguration = configuration.into();
         Self {
+            sites_monitor: health.task(config.task_name("sites")),
+            devices_monitor: health.task(config.task_name("devices")),
+            sites_task: state.task(config.task_name("sites")),
+            devices_task: state.task(config.task_name("devices")),
+            problem: ProblemSensor::new(
+                config.display_name(),
+                config.topics.clone(),
+                mqtt_addr.clone(),
+            ),
+            token_expiry: TokenExpirySensor::new(
+                config.display_name(),
+                config.topics.clone(),
+                mqtt_addr.clone(),
+            ),
+            token_renewal: None,
+            permanent_error: None,
+            config,
             mqtt_addr,
             somfy_client,
+            circuit_breaker,
             sites: HashMap::new(),
         }
     }
 
     fn execute_sites_scraping(act: &mut SomfyActor, ctx: &mut Context<Self>) {
+        if act.permanent_error.is_some() {
+            return;
+        }
+        if let Err(delay) = act.circuit_breaker.try_acquire() {
+            debug!(
+                "Skipping sites scraping, circuit breaker is open for {}",
+                delay.prettify()
+            );
+            return;
+        }
         let client = act.somfy_client.clone();
+        let monitor = act.sites_monitor.clone();
+        let circuit_breaker = act.circuit_breaker.clone();
+        let addr = ctx.address();
+        let retry_delay = SITES_SCRAPE_INTERVAL.to_std().unwrap_or_default();
+        act.sites_task.save_schedule(retry_delay, 0);
         ctx.add_stream(stream! {
-            match client.list_sites().await {
+            let sites = client.list_sites().await;
+            circuit_breaker.record(sites.is_ok());
+            match sites {
                 Ok(sites) =>{
-                    for site in sites {
-                        yield site;
-                    }
+                    monitor.success();
+                    yield ScrapedSites(sites);
+                },
+                Err(error) if error.retry_hint() == RetryHint::Never => {
+                    monitor.abort();
+                    addr.do_send(PermanentFailure(format!("{error:#}")));
+                },
+                Err(error) => {
+                    monitor.failure(retry_delay);
+                    error!("error listing sites: {error:?}")
                 },
-                Err(error) => error!("error listing sites: {error:?}"),
             }
         });
     }
 
     fn execute_devices_scraping(act: &mut SomfyActor, ctx: &mut Context<Self>) {
+        if act.permanent_error.is_some() {
+            return;
+        }
+        let sites: Vec<String> = act
+            .sites
+            .iter()
+            .filter(|(_, alarm_site)| !alarm_site.missing)
+            .map(|(site_id, _)| site_id.clone())
+            .collect();
+        if sites.is_empty() {
+            return;
+        }
+        if let Err(delay) = act.circuit_breaker.try_acquire() {
+            debug!(
+                "Skipping devices scraping, circuit breaker is open for {}",
+                delay.prettify()
+            );
+            return;
+        }
         let client = act.somfy_client.clone();
-        let sites: Vec<String> = act.sites.keys().map(String::clone).collect();
+        let monitor = act.devices_monitor.clone();
+        let circuit_breaker = act.circuit_breaker.clone();
+        let addr = ctx.address();
+        let retry_delay = DEVICES_SCRAPE_INTERVAL.to_std().unwrap_or_default();
         ctx.add_stream(stream! {
+            let mut failed = false;
             for site_id in sites {
                 match client.list_devices(site_id.clone()).await {
-                    Ok(devices) =>{
-                        for device in devices {
-                            yield device;
-                        }
+                    Ok(devices) => yield SiteDevices { site_id, devices },
+                    Err(error) if error.retry_hint() == RetryHint::Never => {
+                        circuit_breaker.record(false);
+                        monitor.abort();
+                        addr.do_send(PermanentFailure(format!("{error:#}")));
+                        return;
+                    },
+                    Err(error) => {
+                        failed = true;
+                        error!("error listing devices for site {site_id}: {error:?}")
                     },
-                    Err(error) => error!("error listing devices for site {site_id}: {error:?}"),
                 }
             }
+            circuit_breaker.record(!failed);
+            if failed {
+                monitor.failure(retry_delay);
+            } else {
+                monitor.success();
+            }
         });
     }
+
+    /// Publishes the expiry of the access token and schedules its renewal, for the scrapings not
+    /// to wait for a new token.
+    fn schedule_token_renewal(&mut self, ctx: &mut Context<Self>) {
+        self.token_expiry.update(self.somfy_client.token_expiry());
+        let Some(renewal_time) = self.somfy_client.token_renewal_time() else {
+            return;
+        };
+        let scheduled = matches!(self.token_renewal, Some((time, _)) if time == renewal_time);
+        if scheduled || self.permanent_error.is_some() {
+            return;
+        }
+        if let Some((_, handle)) = self.token_renewal.take() {
+            ctx.cancel_future(handle);
+        }
+        let delay = cmp::max(renewal_time - Utc::now(), *TOKEN_RENEWAL_MIN_DELAY)
+            .to_std()
+            .unwrap_or_default();
+        let handle = ctx.run_later(delay, |act, ctx| {
+            let client = act.somfy_client.clone();
+            async move { client.renew_token().await }
+                .into_actor(act)
+                .map(|result, act, ctx| {
+                    act.token_renewal = None;
+                    match result {
+                        Ok(()) => act.schedule_token_renewal(ctx),
+                        Err(error) if error.retry_hint() == RetryHint::Never => {
+                            ctx.notify(PermanentFailure(format!("{error:#}")))
+                        }
+                        Err(error) => warn!(
+                            "Unable to renew the token of {}: {error:#}",
+                            act.config.display_name()
+                        ),
+                    }
+                })
+                .spawn(ctx);
+        });
+        self.token_renewal = Some((renewal_time, handle));
+    }
+
+    /// Returns the known site, or an empty one until the site is scraped.
+    fn site(&mut self, site_id: String) -> &mut AlarmSite {
+        self.sites.entry(site_id).or_insert_with_key(|site_id| {
+            let mut empty_site = AlarmSite::new(SiteOutput::default(), self.config.clone());
+            empty_site.site.site_id = site_id.clone();
+            empty_site
+        })
+    }
+
+    fn remove_devices(&self, removed_devices: Vec<AlarmDevice>) {
+        for removed_device in &removed_devices {
+            info!("Removing {removed_device}");
+            self.mqtt_addr.do_send(RemoveDevice {
+                topic_prefix: removed_device.topic_prefix(),
+                entities: removed_device
+                    .collect_entities()
+                    .into_iter()
+                    .map(|entity| entity.0)
+                    .collect(),
+            });
+        }
+    }
+
+    /// Saves the known devices, they are published right away on the next start.
+    fn save_devices(&self) {
+        let devices: Vec<&DeviceOutput> = self
+            .sites
+            .values()
+            .flat_map(|alarm_site| alarm_site.devices.values())
+            .map(|alarm_device| &alarm_device.somfy_device)
+            .collect();
+        self.devices_task.save_data(&devices);
+    }
+
+    fn publish_devices(&self) {
+        self.sites
+            .values()
+            .flat_map(|alarm_site| alarm_site.collect_entities())
+            .for_each(|entity| self.mqtt_addr.do_send(entity));
+        self.sites
+            .values()
+            .flat_map(|alarm_site| alarm_site.devices.values())
+            .for_each(|alarm_device| {
+                self.mqtt_addr.do_send(
+                    PublishEntityData::new(alarm_device.state_topic(), alarm_device.payload())
+                        .stale(alarm_device.stale),
+                )
+            })
+    }
 }
 
 impl Actor for SomfyActor {
@@ -92,71 +323,167 @@ impl Actor for SomfyActor {
         let discovery_interval = sites_scrape_interval
             .to_std()
             .expect("A valid std::Duration");
-        ctx.run_later(std::time::Duration::ZERO, Self::execute_sites_scraping);
-        ctx.run_interval(discovery_interval, Self::execute_sites_scraping);
+        // resume the schedule of the previous run
+        let initial_delay = self.sites_task.initial_delay();
+        ctx.run_later(initial_delay, move |act, ctx| {
+            Self::execute_sites_scraping(act, ctx);
+            ctx.run_interval(discovery_interval, Self::execute_sites_scraping);
+        });
+
+        // devices of the previous run are known until the next scraping
+        let cached_devices = self
+            .devices_task
+            .data::<Vec<DeviceOutput>>()
+            .unwrap_or_default();
+        if !cached_devices.is_empty() {
+            info!(
+                "Publishing last known state of {} devices",
+                cached_devices.len()
+            );
+            for device in cached_devices {
+                self.site(device.site_id.clone()).add_device(device, true);
+            }
+            self.publish_devices();
+        }
 
         let devices_scrape_interval = DEVICES_SCRAPE_INTERVAL.deref();
         info!("Scheduling devices scraping every {devices_scrape_interval}");
         let devices_scrape_interval = devices_scrape_interval
             .to_std()
             .expect("A valid std::Duration");
-        ctx.run_interval(devices_scrape_interval, Self::execute_devices_scraping);
+        ctx.run_later(self.devices_task.initial_delay(), move |_act, ctx| {
+            ctx.run_interval(devices_scrape_interval, Self::execute_devices_scraping);
+        });
     }
 }
 
-impl StreamHandler<SiteOutput> for SomfyActor {
-    fn handle(&mut self, item: SiteOutput, ctx: &mut Self::Context) {
-        self.sites
-            .entry(item.site_id.clone())
-            .and_modify(|known_site| {
-                // TODO: compare site attributes and trigger sensor config update if necessary
-            })
-            .or_insert_with(|| {
-                let new_site = AlarmSite::new(item);
-                info!("Watching {new_site}");
-                new_site
-            });
+impl Handler<Reconfigure> for SomfyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reconfigure, _ctx: &mut Self::Context) -> Self::Result {
+        let Reconfigure(config) = msg;
+        self.config.missing_device_removal_threshold = config.missing_device_removal_threshold;
+    }
+}
+
+/// Stops the scraping after an error retrying can't fix, until the account is reconfigured.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct PermanentFailure(String);
+
+impl Handler<PermanentFailure> for SomfyActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PermanentFailure, _ctx: &mut Self::Context) -> Self::Result {
+        let PermanentFailure(error) = msg;
+        if self.permanent_error.is_none() {
+            error!("Stopped scraping {}: {error}", self.config.display_name());
+            self.problem.raise(error.clone());
+            self.permanent_error = Some(error);
+        }
+    }
+}
+
+impl Handler<Stop> for SomfyActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Stop, ctx: &mut Self::Context) -> Self::Result {
+        self.sites_monitor.remove();
+        self.devices_monitor.remove();
+        ctx.stop();
+    }
+}
+
+/// Every site of the account, listed by a sites scraping.
+struct ScrapedSites(Vec<SiteOutput>);
+
+impl StreamHandler<ScrapedSites> for SomfyActor {
+    fn handle(&mut self, item: ScrapedSites, _ctx: &mut Self::Context) {
+        let ScrapedSites(sites) = item;
+        let site_ids: Vec<String> = sites.iter().map(|site| site.site_id.clone()).collect();
+        for site in sites {
+            self.sites
+                .entry(site.site_id.clone())
+                .and_modify(|known_site| {
+                    // TODO: compare site attributes and trigger sensor config update if necessary
+                    known_site.missing = false;
+                })
+                .or_insert_with(|| {
+                    let new_site = AlarmSite::new(site, self.config.clone());
+                    info!("Watching {new_site}");
+                    new_site
+                });
+        }
+
+        // devices of the sites no longer listed are missing from this scraping too
+        let threshold = self.config.missing_device_removal_threshold;
+        let mut removed_devices = Vec::new();
+        for known_site in self.sites.values_mut() {
+            if site_ids.contains(&known_site.site.site_id) {
+                continue;
+            }
+            if !known_site.missing {
+                warn!("{known_site} is no longer listed");
+                known_site.missing = true;
+            }
+            removed_devices.extend(known_site.remove_missing_devices(&[], threshold));
+        }
+        self.sites.retain(|_, known_site| {
+            let removed = known_site.missing && known_site.devices.is_empty();
+            if removed {
+                info!("Forgetting {known_site}");
+            }
+            !removed
+        });
+        if !removed_devices.is_empty() {
+            self.remove_devices(removed_devices);
+            self.save_devices();
+        }
     }
 
     fn finished(&mut self, ctx: &mut Self::Context) {
         // override default behavior to keep the actor running
+        self.schedule_token_renewal(ctx);
         ctx.run_later(Duration::ZERO, Self::execute_devices_scraping);
     }
 }
 
-impl StreamHandler<DeviceOutput> for SomfyActor {
-    fn handle(&mut self, item: DeviceOutput, ctx: &mut Self::Context) {
-        let site_id = item.site_id.clone();
-        let known_site = self.sites.entry(site_id).or_insert_with_key(|site_id| {
-            let mut empty_site = AlarmSite::new(SiteOutput::default());
-            empty_site.site.site_id = site_id.clone();
-            empty_site
-        });
-        known_site.add_device(item);
+struct SiteDevices {
+    site_id: String,
+    devices: Vec<DeviceOutput>,
+}
+
+impl StreamHandler<SiteDevices> for SomfyActor {
+    fn handle(&mut self, item: SiteDevices, ctx: &mut Self::Context) {
+        let threshold = self.config.missing_device_removal_threshold;
+        let known_site = self.site(item.site_id);
+        let device_ids: Vec<String> = item
+            .devices
+            .iter()
+            .map(|device| device.device_id.clone())
+            .collect();
+        for device in item.devices {
+            known_site.add_device(device, false);
+        }
+        let removed_devices = known_site.remove_missing_devices(&device_ids, threshold);
+        self.remove_devices(removed_devices);
     }
 
     fn finished(&mut self, ctx: &mut Self::Context) {
         // override default behavior to keep the actor running
-        self.sites
-            .values()
-            .flat_map(|alarm_site| alarm_site.collect_entities())
-            .for_each(|entity| self.mqtt_addr.do_send(entity));
-        self.sites
-            .values()
-            .flat_map(|alarm_site| alarm_site.devices.values())
-            .for_each(|alarm_device| {
-                self.mqtt_addr.do_send(PublishEntityData::new(
-                    alarm_device.state_topic(),
-                    alarm_device.payload(),
-                ))
-            })
+        self.schedule_token_renewal(ctx);
+        self.publish_devices();
+        self.save_devices();
     }
 }
 
 struct AlarmSite {
+    config: SomfyActorConfiguration,
     site: SiteOutput,
     devices: HashMap<String, AlarmDevice>,
     box_device_id: Option<String>,
+    /// Not listed by the last sites scraping, its devices are no longer scraped
+    missing: bool,
 }
 
 impl Display for AlarmSite {
@@ -173,27 +500,74 @@ impl Display for AlarmSite {
 }
 
 impl AlarmSite {
-    fn new(site: SiteOutput) -> Self {
+    fn new(site: SiteOutput, config: SomfyActorConfiguration) -> Self {
         Self {
+            config,
             site,
             devices: HashMap::new(),
             box_device_id: None,
+            missing: false,
         }
     }
 
-    fn add_device(&mut self, somfy_device: DeviceOutput) {
+    /// Adds a new device or refreshes the data of a known one, `stale` when the device comes from
+    /// the state saved by a previous run.
+    fn add_device(&mut self, somfy_device: DeviceOutput, stale: bool) {
         if somfy_device.device_definition.r#type == Type::Box {
             self.box_device_id = Some(somfy_device.device_id.clone());
             for alarm_device in self.devices.values_mut() {
                 alarm_device.via_device = self.box_device_id.clone();
             }
         }
-        let device_id = somfy_device.device_id.clone();
-        self.devices.entry(device_id).or_insert_with(|| {
-            let new_device = AlarmDevice::new(somfy_device, self.box_device_id.clone());
-            info!("Watching {new_device}");
-            new_device
-        });
+        match self.devices.entry(somfy_device.device_id.clone()) {
+            Entry::Occupied(mut known_device) => {
+                let known_device = known_device.get_mut();
+                known_device.somfy_device = somfy_device;
+                known_device.stale = stale;
+                known_device.missed_scrapes = 0;
+            }
+            Entry::Vacant(entry) => {
+                let mut new_device = AlarmDevice::new(
+                    somfy_device,
+                    self.box_device_id.clone(),
+                    self.config.clone(),
+                );
+                new_device.stale = stale;
+                info!("Watching {new_device}");
+                entry.insert(new_device);
+            }
+        }
+    }
+
+    /// Forgets devices missing from too many consecutive scrapes, a threshold of 0 keeps them.
+    /// Devices restored from the saved state are forgotten as soon as a scrape misses them.
+    fn remove_missing_devices(
+        &mut self,
+        scraped_device_ids: &[String],
+        threshold: u32,
+    ) -> Vec<AlarmDevice> {
+        for (device_id, device) in self.devices.iter_mut() {
+            if !scraped_device_ids.contains(device_id) {
+                device.missed_scrapes += 1;
+                warn!(
+                    "{device} is missing from the last {} scrapes",
+                    device.missed_scrapes
+                );
+            }
+        }
+        let missing_device_ids: Vec<String> = self
+            .devices
+            .iter()
+            .filter(|(device_id, device)| {
+                (device.stale && !scraped_device_ids.contains(device_id))
+                    || (threshold > 0 && device.missed_scrapes >= threshold)
+            })
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+        missing_device_ids
+            .iter()
+            .filter_map(|device_id| self.devices.remove(device_id))
+            .collect()
     }
 
     fn collect_entities(&self) -> Vec<EntityConfiguration> {
@@ -205,8 +579,12 @@ impl AlarmSite {
 }
 
 struct AlarmDevice {
+    config: SomfyActorConfiguration,
     somfy_device: DeviceOutput,
     via_device: Option<String>,
+    missed_scrapes: u32,
+    /// The device data was saved by a previous run
+    stale: bool,
 }
 
 impl Display for AlarmDevice {
@@ -223,10 +601,17 @@ impl Display for AlarmDevice {
 }
 
 impl AlarmDevice {
-    fn new(somfy_device: DeviceOutput, via_device: Option<String>) -> Self {
+    fn new(
+        somfy_device: DeviceOutput,
+        via_device: Option<String>,
+        config: SomfyActorConfiguration,
+    ) -> Self {
         Self {
+            config,
             somfy_device,
             via_device,
+            missed_scrapes: 0,
+            stale: false,
         }
     }
 
@@ -236,7 +621,7 @@ impl AlarmDevice {
 
         let st = &self.somfy_device.status;
 
-        let mut availability_checks = Vec::new();
+        let mut availability_checks = vec![self.config.topics.bridge_availability()];
         if st.device_lost.is_some() {
             availability_checks.push(
                 AvailabilityCheck::topic("~/state")
@@ -252,6 +637,8 @@ impl AlarmDevice {
         let binary_sensor_defaults = BinarySensor::default()
             .topic_prefix(self.topic_prefix())
             .state_topic(self.state_topic())
+            .json_attributes_topic(self.state_topic())
+            .json_attributes_template(STALE_ATTRIBUTES_TEMPLATE)
             .origin(app_infos::origin())
             .device(self.into())
             .availability(availability.clone())
@@ -261,6 +648,8 @@ impl AlarmDevice {
         let sensor_defaults = Sensor::default()
             .topic_prefix(self.topic_prefix())
             .state_topic(self.state_topic())
+            .json_attributes_topic(self.state_topic())
+            .json_attributes_template(STALE_ATTRIBUTES_TEMPLATE)
             .origin(app_infos::origin())
             .device(self.into())
             .availability(availability);
@@ -630,10 +1019,17 @@ impl HomeAssistantDeviceAttributes for &AlarmDevice {
             .unwrap_or_else(|| format!("{dev_def_label} (id={dev_id})"))
     }
 
+    /// Devices of named accounts include the account name so that accounts sharing a site never
+    /// collide.
     fn unique_id(&self) -> String {
         let somfy_site_id = &self.somfy_device.site_id;
         let somfy_device_id = &self.somfy_device.device_id;
-        format!("{MANUFACTURER}-{somfy_site_id}-{somfy_device_id}").slug()
+        match &self.config.account {
+            Some(account) => {
+                format!("{MANUFACTURER}-{account}-{somfy_site_id}-{somfy_device_id}").slug()
+            }
+            None => format!("{MANUFACTURER}-{somfy_site_id}-{somfy_device_id}").slug(),
+        }
     }
 
     fn object_id(&self) -> String {
@@ -644,8 +1040,9 @@ impl HomeAssistantDeviceAttributes for &AlarmDevice {
     }
 
     fn topic_prefix(&self) -> String {
+        let base_topic = self.config.topics.namespaced(COMMON_BASE_TOPIC);
         let unique_id = self.unique_id();
-        format!("somfy-protect/{unique_id}")
+        format!("{base_topic}/{unique_id}")
     }
 
     fn state_topic(&self) -> String {
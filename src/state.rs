@@ -0,0 +1,339 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    cmp,
+    collections::BTreeMap,
+    fs, io,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::misc::HumanReadable;
+
+/// Changes made meanwhile are saved by the same write
+const WRITE_DELAY: Duration = Duration::from_secs(1);
+
+/// Schedule of the repeated tasks, saved to a file so that a restarted bridge resumes it instead
+/// of calling every cloud API at once.
+#[derive(Clone)]
+pub struct StateStore {
+    /// No task runs before, to space out the API calls of consecutive restarts
+    earliest_run: DateTime<Utc>,
+    state: Arc<Mutex<State>>,
+    /// Saves the state in the background, none without file
+    writer: Option<StateWriter>,
+}
+
+#[derive(Clone)]
+struct StateWriter {
+    file: Arc<StateFile>,
+    changes: mpsc::Sender<()>,
+}
+
+/// Locked while written, for background writes not to overlap with flushes.
+struct StateFile(Mutex<PathBuf>);
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    last_start: Option<DateTime<Utc>>,
+    tasks: BTreeMap<String, TaskState>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct TaskState {
+    next_run: Option<DateTime<Utc>>,
+    #[serde(default)]
+    backoff_position: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl StateStore {
+    /// Reads the state saved by the previous run and records this start, written before returning
+    /// so that an immediate restart is spaced out too. Without file, nothing is saved and tasks
+    /// start immediately.
+    pub fn load(file: Option<PathBuf>, min_restart_interval: Duration) -> Self {
+        let now = Utc::now();
+        let mut state = match &file {
+            Some(file) => read_state(file).unwrap_or_else(|error| {
+                warn!("Ignoring saved state: {error:#}");
+                State::default()
+            }),
+            None => State::default(),
+        };
+        let min_restart_interval = TimeDelta::from_std(min_restart_interval).unwrap_or_default();
+        let earliest_run = state.last_start.map_or(now, |last_start| {
+            cmp::max(now, last_start + min_restart_interval)
+        });
+        if let Ok(delay) = (earliest_run - now).to_std() {
+            if !delay.is_zero() {
+                info!(
+                    "Restarted shortly after the previous run, delaying API calls by {}",
+                    delay.prettify()
+                );
+            }
+        }
+        state.last_start = Some(now);
+        let state = Arc::new(Mutex::new(state));
+        let writer = file.map(|file| StateWriter::spawn(file, state.clone()));
+        let store = Self {
+            earliest_run,
+            state,
+            writer,
+        };
+        store.flush();
+        store
+    }
+
+    /// Writes pending changes at once, the file is otherwise written shortly after changes.
+    pub fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            writer.file.write(&self.state);
+        }
+    }
+
+    /// Returns a handle to the saved state of a task.
+    pub fn task<S: Into<String>>(&self, name: S) -> PersistedTask {
+        PersistedTask {
+            name: name.into(),
+            store: self.clone(),
+        }
+    }
+
+    fn update_task<F: FnOnce(&mut TaskState)>(&self, name: &str, update: F) {
+        let mut state = self.state.lock().unwrap();
+        update(state.tasks.entry(name.to_string()).or_default());
+        drop(state);
+        self.changed();
+    }
+
+    fn changed(&self) {
+        if let Some(writer) = &self.writer {
+            // the writer only stops once every store is dropped
+            let _ = writer.changes.send(());
+        }
+    }
+}
+
+impl StateWriter {
+    /// Starts a thread writing the state shortly after changes, it stops along with the store.
+    fn spawn(file: PathBuf, state: Arc<Mutex<State>>) -> Self {
+        let file = Arc::new(StateFile(Mutex::new(file)));
+        let (changes, on_change) = mpsc::channel();
+        let background_file = file.clone();
+        thread::spawn(move || {
+            while on_change.recv().is_ok() {
+                thread::sleep(WRITE_DELAY);
+                while on_change.try_recv().is_ok() {}
+                background_file.write(&state);
+            }
+        });
+        Self { file, changes }
+    }
+}
+
+impl StateFile {
+    fn write(&self, state: &Mutex<State>) {
+        let file = self.0.lock().unwrap();
+        // the state is only locked while serialized, not during file operations
+        let content = serde_json::to_string_pretty(&*state.lock().unwrap());
+        let result = content
+            .context("Can't serialize state")
+            .and_then(|content| {
+                // replaced at once, a crash never leaves a truncated file
+                let tmp_file = file.with_extension("tmp");
+                fs::write(&tmp_file, content)
+                    .and_then(|_| fs::rename(&tmp_file, &*file))
+                    .with_context(|| format!("Can't write state file {}", file.display()))
+            });
+        if let Err(error) = result {
+            warn!("{error:#}");
+        }
+    }
+}
+
+fn read_state(file: &PathBuf) -> Result<State> {
+    match fs::read_to_string(file) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Invalid state file {}", file.display())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+        Err(error) => {
+            Err(error).with_context(|| format!("Can't read state file {}", file.display()))
+        }
+    }
+}
+
+/// Saved state of a task, see [StateStore].
+#[derive(Clone)]
+pub struct PersistedTask {
+    name: String,
+    store: StateStore,
+}
+
+impl PersistedTask {
+    /// Delay before the first execution: until the saved next run, but never before the minimum
+    /// restart interval is over.
+    pub fn initial_delay(&self) -> Duration {
+        let state = self.store.state.lock().unwrap();
+        let next_run = state
+            .tasks
+            .get(&self.name)
+            .and_then(|task| task.next_run)
+            .map_or(self.store.earliest_run, |next_run| {
+                cmp::max(next_run, self.store.earliest_run)
+            });
+        (next_run - Utc::now()).to_std().unwrap_or_default()
+    }
+
+    /// Position of the backoff policy when the task last ran, [None] if it never did.
+    pub fn backoff_position(&self) -> Option<u32> {
+        let state = self.store.state.lock().unwrap();
+        state
+            .tasks
+            .get(&self.name)
+            .filter(|task| task.next_run.is_some())
+            .map(|task| task.backoff_position)
+    }
+
+    pub fn save_schedule(&self, next_interval: Duration, backoff_position: u32) {
+        let next_run = TimeDelta::from_std(next_interval)
+            .ok()
+            .and_then(|interval| Utc::now().checked_add_signed(interval));
+        self.store.update_task(&self.name, |task| {
+            task.next_run = next_run;
+            task.backoff_position = backoff_position;
+        });
+    }
+
+    /// Data the task needs to resume, such as the result of its last execution.
+    pub fn data<T: DeserializeOwned>(&self) -> Option<T> {
+        let state = self.store.state.lock().unwrap();
+        state
+            .tasks
+            .get(&self.name)
+            .and_then(|task| task.data.clone())
+            .and_then(|data| serde_json::from_value(data).ok())
+    }
+
+    pub fn save_data<T: Serialize>(&self, data: &T) {
+        let data = serde_json::to_value(data).ok();
+        self.store.update_task(&self.name, |task| task.data = data);
+    }
+
+    /// Forgets the task, once it is no longer executed.
+    pub fn remove(&self) {
+        self.store.state.lock().unwrap().tasks.remove(&self.name);
+        self.store.changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{StateStore, WRITE_DELAY};
+
+    fn state_file(name: &str) -> PathBuf {
+        let file = std::env::temp_dir().join(format!(
+            "hass-mqtt-bridge-{name}-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        file
+    }
+
+    #[test]
+    fn resumes_saved_schedule() {
+        let file = state_file("resume");
+        let store = StateStore::load(Some(file.clone()), Duration::ZERO);
+        store
+            .task("rika/status")
+            .save_schedule(Duration::from_secs(600), 3);
+        store.task("rika/discovery").save_data(&vec!["12345"]);
+        store.flush();
+
+        let restarted = StateStore::load(Some(file.clone()), Duration::ZERO);
+        let task = restarted.task("rika/status");
+        assert_eq!(task.backoff_position(), Some(3));
+        let delay = task.initial_delay();
+        assert!(
+            Duration::from_secs(595) < delay && delay <= Duration::from_secs(600),
+            "{delay:?} should be close to 10 minutes"
+        );
+        assert_eq!(
+            restarted.task("rika/discovery").data::<Vec<String>>(),
+            Some(vec!["12345".to_string()])
+        );
+        assert_eq!(restarted.task("unknown").backoff_position(), None);
+        assert_eq!(restarted.task("unknown").initial_delay(), Duration::ZERO);
+
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[test]
+    fn spaces_out_restarts() {
+        let file = state_file("restart");
+        StateStore::load(Some(file.clone()), Duration::from_secs(60)).flush();
+
+        let restarted = StateStore::load(Some(file.clone()), Duration::from_secs(60));
+        let delay = restarted.task("somfy/sites").initial_delay();
+        assert!(
+            Duration::from_secs(55) < delay && delay <= Duration::from_secs(60),
+            "{delay:?} should be close to 1 minute"
+        );
+
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[test]
+    fn records_start_without_waiting() {
+        let file = state_file("immediate-restart");
+        let _store = StateStore::load(Some(file.clone()), Duration::from_secs(60));
+
+        let restarted = StateStore::load(Some(file.clone()), Duration::from_secs(60));
+        let delay = restarted.task("somfy/sites").initial_delay();
+        assert!(
+            Duration::from_secs(55) < delay && delay <= Duration::from_secs(60),
+            "{delay:?} should be close to 1 minute"
+        );
+
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[test]
+    fn saves_changes_in_background() {
+        let file = state_file("background");
+        let store = StateStore::load(Some(file.clone()), Duration::ZERO);
+        store
+            .task("rika/status")
+            .save_schedule(Duration::from_secs(600), 3);
+        store
+            .task("rika/status")
+            .save_schedule(Duration::from_secs(600), 4);
+
+        std::thread::sleep(WRITE_DELAY * 2);
+        let restarted = StateStore::load(Some(file.clone()), Duration::ZERO);
+        assert_eq!(restarted.task("rika/status").backoff_position(), Some(4));
+
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[test]
+    fn starts_immediately_without_state_file() {
+        let store = StateStore::load(None, Duration::from_secs(60));
+        store
+            .task("rika/status")
+            .save_schedule(Duration::from_secs(600), 0);
+
+        let restarted = StateStore::load(None, Duration::from_secs(60));
+        assert_eq!(
+            restarted.task("rika/status").initial_delay(),
+            Duration::ZERO
+        );
+    }
+}
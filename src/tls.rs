@@ -0,0 +1,175 @@
+use anyhow::{bail, Context, Result};
+use rumqttc::TlsConfiguration;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::{fs::File, io::BufReader, path::Path, path::PathBuf, sync::Arc};
+
+/// TLS settings used to connect the MQTT broker.
+#[derive(Clone, Default, Debug)]
+pub struct TlsOptions {
+    /// PEM bundle of trusted certificate authorities, system roots are used when absent.
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate chain for mutual TLS authentication.
+    pub client_cert_file: Option<PathBuf>,
+    /// PEM client private key for mutual TLS authentication.
+    pub client_key_file: Option<PathBuf>,
+    /// Name expected in the server certificate, the broker host is used when absent.
+    pub server_name: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn build(&self) -> Result<TlsConfiguration> {
+        let roots = Arc::new(self.root_certificates()?);
+        let webpki_verifier = WebPkiServerVerifier::builder(roots)
+            .build()
+            .context("invalid certificate authorities")?;
+        let verifier: Arc<dyn ServerCertVerifier> = match &self.server_name {
+            Some(server_name) => Arc::new(PinnedServerNameVerifier {
+                server_name: ServerName::try_from(server_name.as_str())
+                    .with_context(|| format!("invalid TLS server name: {server_name}"))?
+                    .to_owned(),
+                inner: webpki_verifier,
+            }),
+            None => webpki_verifier,
+        };
+
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let config = match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert_file), Some(key_file)) => config
+                .with_client_auth_cert(read_certificates(cert_file)?, read_private_key(key_file)?)
+                .context("invalid client certificate or key")?,
+            (None, None) => config.with_no_client_auth(),
+            (_, _) => bail!("client certificate and client key must be provided together"),
+        };
+        Ok(TlsConfiguration::Rustls(Arc::new(config)))
+    }
+
+    fn root_certificates(&self) -> Result<RootCertStore> {
+        let certificates = match &self.ca_file {
+            Some(ca_file) => read_certificates(ca_file)?,
+            None => rustls_native_certs::load_native_certs()
+                .context("unable to load system certificate authorities")?,
+        };
+        let mut roots = RootCertStore::empty();
+        let (added, ignored) = roots.add_parsable_certificates(certificates);
+        if added == 0 {
+            bail!("no valid certificate authority found ({ignored} ignored)");
+        }
+        Ok(roots)
+    }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("unable to read {path:?}"))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid PEM file {path:?}"))?;
+    if certificates.is_empty() {
+        bail!("no certificate found in {path:?}");
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("unable to read {path:?}"))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("invalid PEM file {path:?}"))?
+        .with_context(|| format!("no private key found in {path:?}"))
+}
+
+/// Verifies the server certificate against a fixed name instead of the host used to connect.
+#[derive(Debug)]
+struct PinnedServerNameVerifier {
+    server_name: ServerName<'static>,
+    inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for PinnedServerNameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.server_name,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::PathBuf};
+
+    use crate::tls::TlsOptions;
+
+    fn write_temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{name}", std::process::id()));
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn can_raise_missing_ca_file_error() {
+        let options = TlsOptions {
+            ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.build().unwrap_err().to_string(),
+            r#"unable to read "/nonexistent/ca.pem""#
+        );
+    }
+
+    #[test]
+    fn can_raise_empty_ca_file_error() {
+        let ca_file = write_temp_file("empty-ca.pem", "not a certificate");
+        let options = TlsOptions {
+            ca_file: Some(ca_file.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.build().unwrap_err().to_string(),
+            format!("no certificate found in {ca_file:?}")
+        );
+    }
+}